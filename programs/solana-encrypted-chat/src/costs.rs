@@ -0,0 +1,99 @@
+//! Rent needed to create each of the program's accounts.
+//!
+//! Accounts are created rent-exempt, so the cost of an instruction is the
+//! sum of the accounts it creates. Every account takes its full
+//! `*_ACCOUNT_SIZE` except a `Message` from `send_message_sized`, which
+//! takes `sized_message_space` of its content length; see
+//! `sized_message_rent`. A first `send_message` into a room, for example,
+//! creates a full `Message`, the recipient's `UserInbox` and the sender's
+//! `SenderState`; later sends only create the `Message`.
+
+use anchor_lang::prelude::Rent;
+
+use crate::*;
+
+/// The program's account types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountKind {
+    ChatRoom,
+    Message,
+    MessageChunk,
+    UserInbox,
+    SenderState,
+    Conversation,
+    ConversationState,
+    KeyRegistry,
+    Block,
+    Reactions,
+    Membership,
+    Mute,
+    Invite,
+    Report,
+    PinnedMessages,
+    TypingState,
+    Presence,
+    TipEscrow,
+    TokenTipEscrow,
+    Contacts,
+    Username,
+    UserProfile,
+    Draft,
+    UserStats,
+    UserSettings,
+    SentMarker,
+    ReadMarker,
+    Session,
+    HiddenMessage,
+    StarredMessage,
+    ReadCursor,
+}
+
+impl AccountKind {
+    /// Size in bytes, including the discriminator.
+    pub const fn size(self) -> usize {
+        match self {
+            AccountKind::ChatRoom => CHAT_ROOM_ACCOUNT_SIZE,
+            AccountKind::Message => MESSAGE_ACCOUNT_SIZE,
+            AccountKind::MessageChunk => MESSAGE_CHUNK_ACCOUNT_SIZE,
+            AccountKind::UserInbox => USER_INBOX_ACCOUNT_SIZE,
+            AccountKind::SenderState => SENDER_STATE_ACCOUNT_SIZE,
+            AccountKind::Conversation => CONVERSATION_ACCOUNT_SIZE,
+            AccountKind::ConversationState => CONVERSATION_STATE_ACCOUNT_SIZE,
+            AccountKind::KeyRegistry => KEY_REGISTRY_ACCOUNT_SIZE,
+            AccountKind::Block => BLOCK_ACCOUNT_SIZE,
+            AccountKind::Reactions => REACTIONS_ACCOUNT_SIZE,
+            AccountKind::Membership => MEMBERSHIP_ACCOUNT_SIZE,
+            AccountKind::Mute => MUTE_ACCOUNT_SIZE,
+            AccountKind::Invite => INVITE_ACCOUNT_SIZE,
+            AccountKind::Report => REPORT_ACCOUNT_SIZE,
+            AccountKind::PinnedMessages => PINNED_MESSAGES_ACCOUNT_SIZE,
+            AccountKind::TypingState => TYPING_STATE_ACCOUNT_SIZE,
+            AccountKind::Presence => PRESENCE_ACCOUNT_SIZE,
+            AccountKind::TipEscrow => TIP_ESCROW_ACCOUNT_SIZE,
+            AccountKind::TokenTipEscrow => TOKEN_TIP_ESCROW_ACCOUNT_SIZE,
+            AccountKind::Contacts => CONTACTS_ACCOUNT_SIZE,
+            AccountKind::Username => USERNAME_ACCOUNT_SIZE,
+            AccountKind::UserProfile => USER_PROFILE_ACCOUNT_SIZE,
+            AccountKind::Draft => DRAFT_ACCOUNT_SIZE,
+            AccountKind::UserStats => USER_STATS_ACCOUNT_SIZE,
+            AccountKind::UserSettings => USER_SETTINGS_ACCOUNT_SIZE,
+            AccountKind::SentMarker => SENT_MARKER_ACCOUNT_SIZE,
+            AccountKind::ReadMarker => READ_MARKER_ACCOUNT_SIZE,
+            AccountKind::Session => SESSION_ACCOUNT_SIZE,
+            AccountKind::HiddenMessage => HIDDEN_MESSAGE_ACCOUNT_SIZE,
+            AccountKind::StarredMessage => STARRED_MESSAGE_ACCOUNT_SIZE,
+            AccountKind::ReadCursor => READ_CURSOR_ACCOUNT_SIZE,
+        }
+    }
+}
+
+/// Lamports that make an account of `kind` rent-exempt.
+pub fn rent_exempt_lamports(rent: &Rent, kind: AccountKind) -> u64 {
+    rent.minimum_balance(kind.size())
+}
+
+/// Lamports that make a `send_message_sized` message holding `content_len`
+/// ciphertext bytes rent-exempt.
+pub fn sized_message_rent(rent: &Rent, content_len: usize) -> u64 {
+    rent.minimum_balance(sized_message_space(content_len))
+}
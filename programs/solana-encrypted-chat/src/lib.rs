@@ -1,101 +1,5611 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::ed25519_program;
+use anchor_lang::solana_program::keccak;
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::system_program;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::token::{self, Mint, Token, TokenAccount};
 
 declare_id!("2ZrfKcAszeddfxEcr5b1zTpSDosQheYpPqiPmyoXQvV4");
 
+pub mod costs;
+pub mod pda;
+mod util;
+
+/// Maximum ciphertext length, matching `#[max_len]` on `Message::encrypted_content`.
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+/// Cipher suite identifiers stored in `Message::algorithm`.
+pub const ALGORITHM_X25519_AES_GCM: u8 = 0;
+pub const ALGORITHM_X25519_CHACHA20_POLY1305: u8 = 1;
+
+/// Cipher suites `send_message` accepts. Append new suites here; never reuse ids.
+pub const SUPPORTED_ALGORITHMS: [u8; 2] = [
+    ALGORITHM_X25519_AES_GCM,
+    ALGORITHM_X25519_CHACHA20_POLY1305,
+];
+
+/// Length of the AEAD nonce stored alongside each ciphertext (large enough for XChaCha20).
+pub const NONCE_LEN: usize = 24;
+
+/// Shortest ciphertext `send_message` accepts when flagged `compressed`: a
+/// 16-byte AEAD tag around the smallest non-empty deflate stream. Anything
+/// shorter cannot decrypt to compressed data.
+pub const MIN_COMPRESSED_LEN: usize = 16 + 2;
+
+/// Length of the optional `send_message` idempotency key; see `SentMarker`.
+pub const CLIENT_NONCE_LEN: usize = 16;
+
+/// Number of reaction kinds tracked per message (👍 ❤️ 😂 😮 😢 🙏).
+pub const REACTION_KINDS: usize = 6;
+
+/// `Message::attachment_kind` values. Attachments live off-chain (IPFS or
+/// Arweave), encrypted like the message body; only their CID is stored.
+pub const ATTACHMENT_NONE: u8 = 0;
+pub const ATTACHMENT_IMAGE: u8 = 1;
+pub const ATTACHMENT_FILE: u8 = 2;
+pub const ATTACHMENT_AUDIO: u8 = 3;
+
+/// Maximum attachment CID length, matching `#[max_len]` on `Message::attachment_cid`.
+pub const MAX_CID_LEN: usize = 64;
+
+/// `Message::priority` values, letting clients surface urgent messages
+/// such as security alerts differently.
+pub const PRIORITY_NORMAL: u8 = 0;
+pub const PRIORITY_HIGH: u8 = 1;
+pub const PRIORITY_URGENT: u8 = 2;
+
+/// Prior-ciphertext hashes kept per message, matching `#[max_len]` on
+/// `Message::prev_content_hashes`.
+pub const MAX_EDIT_HISTORY: usize = 5;
+
+/// Largest room `send_group_message` serves, matching `#[max_len]` on
+/// `Message::sealed_keys`.
+pub const MAX_SEALED_KEYS: usize = 6;
+
+/// A message key sealed to one member's public key: 32 key bytes plus a
+/// 16-byte authentication tag.
+pub const SEALED_KEY_LEN: usize = 48;
+
+/// Number of report reasons `report_message` accepts: spam, harassment,
+/// illegal content, other.
+pub const REPORT_REASONS: u8 = 4;
+
+/// Maximum ciphertext bytes per `MessageChunk`, matching its `#[max_len]`.
+pub const MAX_CHUNK_LEN: usize = 900;
+
+/// Upper bound on `chunk_count` for a single large message.
+pub const MAX_CHUNKS: u16 = 64;
+
+/// Byte offset of `Message::sender` in account data (after the 8-byte
+/// discriminator and the `version` byte), for `getProgramAccounts` `memcmp`
+/// filters.
+#[constant]
+pub const SENDER_OFFSET: u32 = 9;
+
+/// Byte offset of `Message::recipient` in account data.
+#[constant]
+pub const RECIPIENT_OFFSET: u32 = 41;
+
+/// Current layouts of `Message` and `ChatRoom`, stored in their leading
+/// `version` byte. Version 1 is the unversioned layout that predates it;
+/// `Message` versions 3 to 13 added `content_hash`, `session_epoch`,
+/// `is_system`, `view_once`, `reply_count`, `star_count`,
+/// `quoted_content_hash`, `security_epoch`, `compressed`, `sealed_keys` and
+/// `signature_verified`;
+/// `ChatRoom` versions 3 to 8 added `pending_authority`, `default_ttl_secs`,
+/// `slow_mode_secs`, `last_room_message_at`, `security_epoch`,
+/// `min_sender_balance` and `rate_limit_min_interval_secs`.
+#[constant]
+pub const MESSAGE_VERSION: u8 = 13;
+#[constant]
+pub const CHAT_ROOM_VERSION: u8 = 8;
+
+/// Account sizes of earlier `Message` layouts. Messages are allocated at
+/// full size, or below `MESSAGE_V1_ACCOUNT_SIZE` by `send_message_sized`, so
+/// the size identifies the layout.
+pub const MESSAGE_V1_ACCOUNT_SIZE: usize = 1044;
+pub const MESSAGE_V2_ACCOUNT_SIZE: usize = 1045;
+pub const MESSAGE_V3_ACCOUNT_SIZE: usize = 1077;
+pub const MESSAGE_V4_ACCOUNT_SIZE: usize = 1086;
+pub const MESSAGE_V5_ACCOUNT_SIZE: usize = 1087;
+pub const MESSAGE_V6_ACCOUNT_SIZE: usize = 1088;
+pub const MESSAGE_V7_ACCOUNT_SIZE: usize = 1092;
+pub const MESSAGE_V8_ACCOUNT_SIZE: usize = 1096;
+pub const MESSAGE_V9_ACCOUNT_SIZE: usize = 1129;
+pub const MESSAGE_V10_ACCOUNT_SIZE: usize = 1137;
+pub const MESSAGE_V11_ACCOUNT_SIZE: usize = 1138;
+pub const MESSAGE_V12_ACCOUNT_SIZE: usize = 1430;
+
+/// Smallest account `send_message_sized` creates: one content byte in the
+/// version 6 layout, the first it was available in. Fixed rather than
+/// derived from `sized_message_space`, which grows with every new field,
+/// so sized messages from every layout since stay readable.
+pub const MIN_SIZED_MESSAGE_ACCOUNT_SIZE: usize = 577;
+
+/// Space for a `send_message_sized` message holding `content_len` bytes: the
+/// full size less the content buffer and the group keys it does not use.
+/// Optional fields and the edit history keep their room, so the message may
+/// still be delivered, read and edited to content no longer than the
+/// original. Sizes that would reach the legacy layouts fall back to the full
+/// size.
+pub const fn sized_message_space(content_len: usize) -> usize {
+    let unused = MAX_MESSAGE_LEN + MAX_SEALED_KEYS * SEALED_KEY_LEN;
+    let space = MESSAGE_ACCOUNT_SIZE - unused + content_len;
+    if space < MESSAGE_V1_ACCOUNT_SIZE {
+        space
+    } else {
+        MESSAGE_ACCOUNT_SIZE
+    }
+}
+
+/// Longest content that fits a message account of `data_len` bytes while
+/// keeping the room `sized_message_space` sets aside for the other fields:
+/// the original length for a `send_message_sized` message, at least
+/// `MAX_MESSAGE_LEN` for a full-size one. Sized messages from older layouts
+/// get less, having less room to begin with.
+pub const fn message_content_capacity(data_len: usize) -> usize {
+    let unused = MAX_MESSAGE_LEN + MAX_SEALED_KEYS * SEALED_KEY_LEN;
+    (data_len + unused).saturating_sub(MESSAGE_ACCOUNT_SIZE)
+}
+
+/// How long after sending a sender may still `unsend_message`. This is a
+/// program-wide constant for now; making it per-room would mean adding a
+/// `recall_window_secs` field to `ChatRoom` and checking that instead.
+pub const RECALL_WINDOW_SECS: i64 = 120;
+
+/// How long after sending a sender may still `edit_message`.
+pub const EDIT_WINDOW_SECS: i64 = 15 * 60;
+
+/// Furthest into the future a client-supplied deadline may lie.
+pub const MAX_DEADLINE_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// Room id of the original single global chat room. Clients that predate
+/// multi-room support should initialize and send to this room.
+pub const LEGACY_ROOM_ID: u64 = 0;
+
+/// Number of recent messages kept in each `UserInbox`, matching its `#[max_len]`.
+pub const INBOX_CAPACITY: usize = 16;
+
+/// Entries a `Contacts` list can hold, matching its `#[max_len]`.
+pub const MAX_CONTACTS: usize = 32;
+
+/// Most recipients a single `batch_send` may address.
+pub const MAX_BATCH_RECIPIENTS: usize = 8;
+
+/// Most messages a single `reap_expired_batch` may inspect.
+pub const MAX_REAP_BATCH: usize = 10;
+
+/// Most messages a single `purge_messages` call may close.
+pub const MAX_PURGE_BATCH: u16 = 10;
+
+/// `Presence::status` values.
+pub const PRESENCE_OFFLINE: u8 = 0;
+pub const PRESENCE_ONLINE: u8 = 1;
+pub const PRESENCE_AWAY: u8 = 2;
+
+/// Clients heartbeat `set_presence`; once `last_seen` is older than this the
+/// user should be shown offline whatever the stored status says.
+#[constant]
+pub const PRESENCE_STALE_SECS: i64 = 60;
+
+/// `Membership::role` values, ordered so that `role >= ROLE_MODERATOR`
+/// reads as "may moderate". The room authority always acts as an admin.
+pub const ROLE_MEMBER: u8 = 0;
+pub const ROLE_MODERATOR: u8 = 1;
+pub const ROLE_ADMIN: u8 = 2;
+
+/// Number of messages a room can pin, matching `#[max_len]` on `PinnedMessages`.
+pub const MAX_PINNED: usize = 5;
+
+/// Age after which clients should treat a `TypingState` as stale, since a
+/// client that disconnects mid-message never clears its indicator.
+#[constant]
+pub const TYPING_STALE_SECS: i64 = 10;
+
+/// Full on-chain size of each account type: the 8-byte discriminator plus
+/// `InitSpace`. Use with `costs` to work out the rent an instruction needs.
+pub const CHAT_ROOM_ACCOUNT_SIZE: usize = 8 + ChatRoom::INIT_SPACE;
+pub const MESSAGE_ACCOUNT_SIZE: usize = 8 + Message::INIT_SPACE;
+pub const MESSAGE_CHUNK_ACCOUNT_SIZE: usize = 8 + MessageChunk::INIT_SPACE;
+pub const USER_INBOX_ACCOUNT_SIZE: usize = 8 + UserInbox::INIT_SPACE;
+pub const SENDER_STATE_ACCOUNT_SIZE: usize = 8 + SenderState::INIT_SPACE;
+pub const CONVERSATION_ACCOUNT_SIZE: usize = 8 + Conversation::INIT_SPACE;
+pub const CONVERSATION_STATE_ACCOUNT_SIZE: usize = 8 + ConversationState::INIT_SPACE;
+pub const KEY_REGISTRY_ACCOUNT_SIZE: usize = 8 + KeyRegistry::INIT_SPACE;
+pub const BLOCK_ACCOUNT_SIZE: usize = 8 + Block::INIT_SPACE;
+pub const REACTIONS_ACCOUNT_SIZE: usize = 8 + Reactions::INIT_SPACE;
+pub const MEMBERSHIP_ACCOUNT_SIZE: usize = 8 + Membership::INIT_SPACE;
+pub const MUTE_ACCOUNT_SIZE: usize = 8 + Mute::INIT_SPACE;
+pub const INVITE_ACCOUNT_SIZE: usize = 8 + Invite::INIT_SPACE;
+pub const REPORT_ACCOUNT_SIZE: usize = 8 + Report::INIT_SPACE;
+pub const PINNED_MESSAGES_ACCOUNT_SIZE: usize = 8 + PinnedMessages::INIT_SPACE;
+pub const TYPING_STATE_ACCOUNT_SIZE: usize = 8 + TypingState::INIT_SPACE;
+pub const PRESENCE_ACCOUNT_SIZE: usize = 8 + Presence::INIT_SPACE;
+pub const TIP_ESCROW_ACCOUNT_SIZE: usize = 8 + TipEscrow::INIT_SPACE;
+pub const TOKEN_TIP_ESCROW_ACCOUNT_SIZE: usize = 8 + TokenTipEscrow::INIT_SPACE;
+pub const CONTACTS_ACCOUNT_SIZE: usize = 8 + Contacts::INIT_SPACE;
+pub const USERNAME_ACCOUNT_SIZE: usize = 8 + Username::INIT_SPACE;
+pub const USER_PROFILE_ACCOUNT_SIZE: usize = 8 + UserProfile::INIT_SPACE;
+pub const DRAFT_ACCOUNT_SIZE: usize = 8 + Draft::INIT_SPACE;
+pub const USER_STATS_ACCOUNT_SIZE: usize = 8 + UserStats::INIT_SPACE;
+pub const USER_SETTINGS_ACCOUNT_SIZE: usize = 8 + UserSettings::INIT_SPACE;
+pub const SENT_MARKER_ACCOUNT_SIZE: usize = 8 + SentMarker::INIT_SPACE;
+pub const READ_MARKER_ACCOUNT_SIZE: usize = 8 + ReadMarker::INIT_SPACE;
+pub const SESSION_ACCOUNT_SIZE: usize = 8 + Session::INIT_SPACE;
+pub const HIDDEN_MESSAGE_ACCOUNT_SIZE: usize = 8 + HiddenMessage::INIT_SPACE;
+pub const STARRED_MESSAGE_ACCOUNT_SIZE: usize = 8 + StarredMessage::INIT_SPACE;
+pub const READ_CURSOR_ACCOUNT_SIZE: usize = 8 + ReadCursor::INIT_SPACE;
+
 #[program]
 pub mod solana_encrypted_chat {
     use super::*;
 
-    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
-        let chat_room = &mut ctx.accounts.chat_room;
-        chat_room.message_count = 0;
-        msg!("Chat room initialized!");
-        Ok(())
+    pub fn initialize(ctx: Context<Initialize>, room_id: u64, config: RoomConfig) -> Result<()> {
+        require!(
+            usize::from(config.max_message_len) <= MAX_MESSAGE_LEN,
+            ChatError::ConfigTooLarge
+        );
+        validate_room_ttl(config.default_ttl_secs)?;
+
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.message_count = 0;
+        chat_room.version = CHAT_ROOM_VERSION;
+        chat_room.room_id = room_id;
+        chat_room.authority = ctx.accounts.user.key();
+        chat_room.rate_limit_window_secs = config.rate_limit_window_secs;
+        chat_room.rate_limit_max_messages = config.rate_limit_max_messages;
+        chat_room.gated = config.gated;
+        chat_room.max_message_len = config.max_message_len;
+        chat_room.default_ttl_secs = config.default_ttl_secs;
+        chat_room.min_sender_balance = config.min_sender_balance;
+        chat_room.rate_limit_min_interval_secs = config.rate_limit_min_interval_secs;
+        msg!("Chat room {} initialized!", room_id);
+
+        emit!(ChatRoomInitialized {
+            chat_room: chat_room.key(),
+            room_id,
+        });
+        Ok(())
+    }
+
+    /// First step of an authority handoff. Nothing changes until
+    /// `new_authority` signs `accept_authority`, so a mistyped or unusable
+    /// address cannot take the room. Proposing again replaces the candidate.
+    pub fn propose_authority(
+        ctx: Context<UpdateChatRoom>,
+        new_authority: Pubkey,
+    ) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.pending_authority = Some(new_authority);
+        msg!(
+            "Chat room {} authority proposed to {}",
+            chat_room.room_id,
+            new_authority
+        );
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        let new_authority = ctx.accounts.new_authority.key();
+        require!(
+            chat_room.pending_authority == Some(new_authority),
+            ChatError::NotPendingAuthority
+        );
+        msg!(
+            "Chat room {} authority transferred from {} to {}",
+            chat_room.room_id,
+            chat_room.authority,
+            new_authority
+        );
+        chat_room.authority = new_authority;
+        chat_room.pending_authority = None;
+        Ok(())
+    }
+
+    /// Sets or clears the room's disappearing-messages policy. Applies to
+    /// messages sent from now on; existing messages keep their expiry.
+    pub fn set_room_ttl(ctx: Context<UpdateChatRoom>, default_ttl_secs: Option<u32>) -> Result<()> {
+        validate_room_ttl(default_ttl_secs)?;
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.default_ttl_secs = default_ttl_secs;
+        msg!(
+            "Chat room {} message TTL set to {:?}",
+            chat_room.room_id,
+            default_ttl_secs
+        );
+        Ok(())
+    }
+
+    /// Sets the room's slow mode; 0 turns it off.
+    pub fn set_slow_mode(ctx: Context<UpdateChatRoom>, slow_mode_secs: u32) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.slow_mode_secs = slow_mode_secs;
+        msg!(
+            "Chat room {} slow mode set to {}s",
+            chat_room.room_id,
+            slow_mode_secs
+        );
+        Ok(())
+    }
+
+    /// Marks every message sent so far as untrusted after a suspected
+    /// compromise of the room's shared keys. Nothing is deleted; clients
+    /// compare each message's `security_epoch` with the room's and treat
+    /// older ones as unverified.
+    pub fn bump_security_epoch(ctx: Context<UpdateChatRoom>) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.security_epoch = chat_room
+            .security_epoch
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
+
+        emit!(SecurityEpochBumped {
+            chat_room: chat_room.key(),
+            security_epoch: chat_room.security_epoch,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_authority_transfer(ctx: Context<UpdateChatRoom>) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.pending_authority = None;
+        msg!("Chat room {} authority transfer cancelled", chat_room.room_id);
+        Ok(())
+    }
+
+    /// Freezes a room: new sends are rejected, while reads, edits and
+    /// deletes of existing messages keep working.
+    pub fn pause_room(ctx: Context<ModerateRoom>) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        require_role(
+            chat_room,
+            ctx.accounts.moderator.key,
+            ctx.accounts.moderator_membership.as_deref(),
+            ROLE_MODERATOR,
+        )?;
+        chat_room.paused = true;
+        emit!(RoomPaused {
+            chat_room: chat_room.key(),
+        });
+        Ok(())
+    }
+
+    pub fn unpause_room(ctx: Context<ModerateRoom>) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        require_role(
+            chat_room,
+            ctx.accounts.moderator.key,
+            ctx.accounts.moderator_membership.as_deref(),
+            ROLE_MODERATOR,
+        )?;
+        chat_room.paused = false;
+        emit!(RoomUnpaused {
+            chat_room: chat_room.key(),
+        });
+        Ok(())
+    }
+
+    pub fn add_member(ctx: Context<AddMember>, member: Pubkey) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.member_count = chat_room
+            .member_count
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
+
+        let membership = &mut ctx.accounts.membership;
+        membership.room = chat_room.key();
+        membership.member = member;
+        membership.role = ROLE_MEMBER;
+
+        msg!("{} joined chat room {}", member, chat_room.room_id);
+        Ok(())
+    }
+
+    /// Moderators may remove plain members; removing a moderator or admin
+    /// takes at least the same role.
+    pub fn remove_member(ctx: Context<RemoveMember>) -> Result<()> {
+        let chat_room = &mut ctx.accounts.chat_room;
+        let target_role = ctx.accounts.membership.role;
+        require_role(
+            chat_room,
+            ctx.accounts.moderator.key,
+            ctx.accounts.moderator_membership.as_deref(),
+            target_role.max(ROLE_MODERATOR),
+        )?;
+
+        chat_room.member_count = chat_room.member_count.saturating_sub(1);
+        if target_role == ROLE_ADMIN {
+            chat_room.admin_count = chat_room.admin_count.saturating_sub(1);
+        }
+
+        msg!(
+            "{} removed from chat room {}",
+            ctx.accounts.membership.member,
+            chat_room.room_id
+        );
+        Ok(())
+    }
+
+    /// Creates an invite to the room redeemable `max_uses` times. Only the
+    /// keccak hash of the code is stored, so the code itself stays secret
+    /// until someone redeems it.
+    pub fn create_invite(
+        ctx: Context<CreateInvite>,
+        code_hash: [u8; 32],
+        max_uses: u16,
+    ) -> Result<()> {
+        let invite = &mut ctx.accounts.invite;
+        invite.room = ctx.accounts.chat_room.key();
+        invite.code_hash = code_hash;
+        invite.remaining_uses = max_uses;
+        msg!("Invite created for chat room {}", ctx.accounts.chat_room.room_id);
+        Ok(())
+    }
+
+    /// Joins a room with an invite code, creating the redeemer's `Membership`.
+    pub fn redeem_invite(ctx: Context<RedeemInvite>, code: Vec<u8>) -> Result<()> {
+        let invite = &mut ctx.accounts.invite;
+        require!(
+            keccak::hash(&code).to_bytes() == invite.code_hash,
+            ChatError::InvalidInviteCode
+        );
+        require!(invite.remaining_uses > 0, ChatError::InviteExhausted);
+        invite.remaining_uses -= 1;
+
+        let chat_room = &mut ctx.accounts.chat_room;
+        chat_room.member_count = chat_room
+            .member_count
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
+
+        let membership = &mut ctx.accounts.membership;
+        membership.room = chat_room.key();
+        membership.member = ctx.accounts.redeemer.key();
+        membership.role = ROLE_MEMBER;
+
+        msg!("{} joined chat room {} by invite", membership.member, chat_room.room_id);
+        Ok(())
+    }
+
+    /// Changes a member's role. Only admins may call this, and the last
+    /// admin cannot step down, so a room is never left without one.
+    pub fn set_role(ctx: Context<SetRole>, member: Pubkey, role: u8) -> Result<()> {
+        require!(role <= ROLE_ADMIN, ChatError::InvalidRole);
+        let chat_room = &mut ctx.accounts.chat_room;
+        let admin = ctx.accounts.admin.key();
+        require_role(
+            chat_room,
+            &admin,
+            ctx.accounts.admin_membership.as_deref(),
+            ROLE_ADMIN,
+        )?;
+
+        let membership = &mut ctx.accounts.membership;
+        let previous = membership.role;
+        if previous == ROLE_ADMIN && role != ROLE_ADMIN {
+            require!(
+                member != admin || chat_room.admin_count > 1,
+                ChatError::LastAdmin
+            );
+            chat_room.admin_count = chat_room.admin_count.saturating_sub(1);
+        } else if previous != ROLE_ADMIN && role == ROLE_ADMIN {
+            chat_room.admin_count = chat_room
+                .admin_count
+                .checked_add(1)
+                .ok_or(ChatError::CounterOverflow)?;
+        }
+        membership.role = role;
+
+        msg!(
+            "{} now has role {} in chat room {}",
+            member,
+            role,
+            chat_room.room_id
+        );
+        Ok(())
+    }
+
+    pub fn pin_message(ctx: Context<PinMessage>, message_id: u64) -> Result<()> {
+        let chat_room = &ctx.accounts.chat_room;
+        require_role(
+            chat_room,
+            ctx.accounts.moderator.key,
+            ctx.accounts.moderator_membership.as_deref(),
+            ROLE_MODERATOR,
+        )?;
+        require!(
+            message_id < chat_room.message_count,
+            ChatError::MessageNotFound
+        );
+
+        let pinned = &mut ctx.accounts.pinned;
+        pinned.room = chat_room.key();
+        pinned.pin(message_id)?;
+
+        emit!(MessagePinned {
+            chat_room: pinned.room,
+            message_id,
+        });
+        Ok(())
+    }
+
+    pub fn unpin_message(ctx: Context<UnpinMessage>, message_id: u64) -> Result<()> {
+        require_role(
+            &ctx.accounts.chat_room,
+            ctx.accounts.moderator.key,
+            ctx.accounts.moderator_membership.as_deref(),
+            ROLE_MODERATOR,
+        )?;
+        let pinned = &mut ctx.accounts.pinned;
+        pinned.unpin(message_id)?;
+
+        emit!(MessageUnpinned {
+            chat_room: pinned.room,
+            message_id,
+        });
+        Ok(())
+    }
+
+    /// Closes a chat room and refunds its rent to the authority.
+    ///
+    /// Outstanding messages do not block closing: they live in their own
+    /// accounts and remain readable and deletable by their senders. Because
+    /// message PDAs are seeded by the room address, re-initializing the same
+    /// room id would collide with surviving messages, so pick a fresh id.
+    pub fn close_chat_room(ctx: Context<CloseChatRoom>) -> Result<()> {
+        msg!("Chat room {} closed", ctx.accounts.chat_room.room_id);
+        Ok(())
+    }
+
+    /// `quoted_content_hash` records the `content_hash` of the `reply_to`
+    /// parent the client saw, so a later edit of the parent cannot change
+    /// what the reply appears to answer. It is checked against the parent,
+    /// which replies always pass, and fails with `QuoteMismatch` if the
+    /// parent has changed since.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_message(
+        ctx: Context<SendMessage>,
+        _room_id: u64,
+        encrypted_message: Vec<u8>,
+        recipient: Pubkey,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+        reply_to: Option<u64>,
+        expires_at: Option<i64>,
+        content_signature: [u8; 64],
+        attachment: Option<Attachment>,
+        priority: u8,
+        client_nonce: Option<[u8; CLIENT_NONCE_LEN]>,
+        view_once: bool,
+        quoted_content_hash: Option<[u8; 32]>,
+        compressed: bool,
+    ) -> Result<()> {
+        require!(
+            !compressed || encrypted_message.len() >= MIN_COMPRESSED_LEN,
+            ChatError::InvalidCompressedPayload
+        );
+        if let Some(quoted) = &quoted_content_hash {
+            let parent = ctx
+                .accounts
+                .parent
+                .as_ref()
+                .filter(|_| reply_to.is_some())
+                .ok_or(ChatError::InvalidReplyTarget)?;
+            require!(parent.content_hash == *quoted, ChatError::QuoteMismatch);
+        }
+        let mut marker_rent = 0;
+        if let Some(client_nonce) = client_nonce {
+            claim_client_nonce(ctx.accounts, &client_nonce)?;
+            marker_rent = ctx.accounts.sent_marker.as_ref().map_or(0, |m| m.lamports());
+        }
+        process_send_message(
+            ctx.accounts,
+            marker_rent,
+            encrypted_message,
+            recipient,
+            algorithm,
+            nonce,
+            reply_to,
+            expires_at,
+            content_signature,
+            attachment,
+            priority,
+        )?;
+        ctx.accounts.message.view_once = view_once;
+        ctx.accounts.message.quoted_content_hash = quoted_content_hash;
+        ctx.accounts.message.compressed = compressed;
+        Ok(())
+    }
+
+    /// Sends a message and escrows `amount_lamports` for its recipient to
+    /// collect with `claim_tip`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_message_with_tip(
+        ctx: Context<SendMessageWithTip>,
+        _room_id: u64,
+        encrypted_message: Vec<u8>,
+        recipient: Pubkey,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+        reply_to: Option<u64>,
+        expires_at: Option<i64>,
+        content_signature: [u8; 64],
+        attachment: Option<Attachment>,
+        priority: u8,
+        amount_lamports: u64,
+    ) -> Result<()> {
+        require!(amount_lamports > 0, ChatError::ZeroTip);
+        process_send_message(
+            &mut ctx.accounts.send,
+            ctx.accounts.tip_escrow.to_account_info().lamports(),
+            encrypted_message,
+            recipient,
+            algorithm,
+            nonce,
+            reply_to,
+            expires_at,
+            content_signature,
+            attachment,
+            priority,
+        )?;
+
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.send.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.send.sender.to_account_info(),
+                    to: ctx.accounts.tip_escrow.to_account_info(),
+                },
+            ),
+            amount_lamports,
+        )?;
+
+        let tip_escrow = &mut ctx.accounts.tip_escrow;
+        tip_escrow.message = ctx.accounts.send.message.key();
+        tip_escrow.recipient = recipient;
+        tip_escrow.amount_lamports = amount_lamports;
+
+        msg!("Tip of {} lamports escrowed for {}", amount_lamports, recipient);
+        Ok(())
+    }
+
+    /// Like `send_message_with_tip`, but escrows `amount` of an SPL token
+    /// in a vault owned by the `TokenTipEscrow` PDA.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_message_with_token_tip(
+        ctx: Context<SendMessageWithTokenTip>,
+        _room_id: u64,
+        encrypted_message: Vec<u8>,
+        recipient: Pubkey,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+        reply_to: Option<u64>,
+        expires_at: Option<i64>,
+        content_signature: [u8; 64],
+        attachment: Option<Attachment>,
+        priority: u8,
+        amount: u64,
+    ) -> Result<()> {
+        require!(amount > 0, ChatError::ZeroTip);
+        let escrow_rent = ctx
+            .accounts
+            .token_tip_escrow
+            .to_account_info()
+            .lamports()
+            .saturating_add(ctx.accounts.vault.to_account_info().lamports());
+        process_send_message(
+            &mut ctx.accounts.send,
+            escrow_rent,
+            encrypted_message,
+            recipient,
+            algorithm,
+            nonce,
+            reply_to,
+            expires_at,
+            content_signature,
+            attachment,
+            priority,
+        )?;
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.sender_token.to_account_info(),
+                    to: ctx.accounts.vault.to_account_info(),
+                    authority: ctx.accounts.send.sender.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let escrow = &mut ctx.accounts.token_tip_escrow;
+        escrow.message = ctx.accounts.send.message.key();
+        escrow.recipient = recipient;
+        escrow.mint = ctx.accounts.mint.key();
+        escrow.amount = amount;
+
+        msg!("Tip of {} {} escrowed for {}", amount, escrow.mint, recipient);
+        Ok(())
+    }
+
+    /// Moves an escrowed token tip to the recipient's associated token
+    /// account, then closes the vault and escrow.
+    pub fn claim_token_tip(ctx: Context<ClaimTokenTip>) -> Result<()> {
+        let escrow = &ctx.accounts.token_tip_escrow;
+        let bump = [ctx.bumps.token_tip_escrow];
+        let signer_seeds: &[&[&[u8]]] = &[&[b"token_tip", escrow.message.as_ref(), &bump]];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.vault.to_account_info(),
+                    to: ctx.accounts.recipient_token.to_account_info(),
+                    authority: escrow.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            escrow.amount,
+        )?;
+        token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::CloseAccount {
+                account: ctx.accounts.vault.to_account_info(),
+                destination: ctx.accounts.recipient.to_account_info(),
+                authority: escrow.to_account_info(),
+            },
+            signer_seeds,
+        ))?;
+
+        msg!(
+            "{} claimed a tip of {} {}",
+            escrow.recipient,
+            escrow.amount,
+            escrow.mint
+        );
+        Ok(())
+    }
+
+    /// Pays the escrowed tip, plus the escrow's rent, to the recipient.
+    pub fn claim_tip(ctx: Context<ClaimTip>) -> Result<()> {
+        msg!(
+            "{} claimed a tip of {} lamports",
+            ctx.accounts.recipient.key(),
+            ctx.accounts.tip_escrow.amount_lamports
+        );
+        Ok(())
+    }
+
+    /// Sends one message per recipient in a single transaction. Each recipient
+    /// gets its own ciphertext and nonce, aligned by index with `recipients`.
+    ///
+    /// `remaining_accounts` holds, per recipient and in order, the message
+    /// PDA, the recipient's inbox PDA, the recipient's `Block` PDA for the
+    /// sender and the recipient's `UserStats` PDA, exactly as `SendMessage`
+    /// would take them.
+    pub fn batch_send<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchSend<'info>>,
+        _room_id: u64,
+        recipients: Vec<Pubkey>,
+        ciphertexts: Vec<Vec<u8>>,
+        algorithm: u8,
+        nonces: Vec<[u8; NONCE_LEN]>,
+    ) -> Result<()> {
+        require!(
+            !recipients.is_empty() && recipients.len() <= MAX_BATCH_RECIPIENTS,
+            ChatError::BatchTooLarge
+        );
+        require!(
+            ciphertexts.len() == recipients.len() && nonces.len() == recipients.len(),
+            ChatError::BatchLengthMismatch
+        );
+        require!(
+            ctx.remaining_accounts.len() == 4 * recipients.len(),
+            ErrorCode::AccountNotEnoughKeys
+        );
+
+        let accounts = ctx.accounts;
+        let sender_info = accounts.sender.to_account_info();
+        let system_info = accounts.system_program.to_account_info();
+        let sender = sender_info.key();
+        let room = accounts.chat_room.key();
+        let now = Clock::get()?.unix_timestamp;
+        // The batch is one send as far as slow mode and rate limits go;
+        // only blocks differ per recipient and are checked below.
+        let sender_balance =
+            balance_before_send(&sender_info, None, None, &accounts.sender_state);
+        authorize_room_send(
+            &mut accounts.chat_room,
+            sender_balance,
+            accounts.membership.is_some(),
+            None,
+            &mut accounts.sender_state,
+            now,
+        )?;
+
+        let entries = recipients.into_iter().zip(ciphertexts).zip(nonces);
+        for (((recipient, content), nonce), infos) in
+            entries.zip(ctx.remaining_accounts.chunks_exact(4))
+        {
+            let [message_info, inbox_info, block_info, stats_info] = infos else {
+                unreachable!("chunks_exact(4) yields slices of four");
+            };
+            validate_ciphertext(&content, algorithm, &nonce)?;
+            accounts.chat_room.check_message_len(content.len())?;
+
+            let (block, _) = pda::block_pda(&crate::ID, &recipient, &sender);
+            require_keys_eq!(block_info.key(), block, ErrorCode::ConstraintSeeds);
+            require!(!is_initialized(block_info), ChatError::SenderBlocked);
+
+            let mut message = Message::default();
+            fill_message(&mut message, &mut accounts.chat_room, sender, recipient, None, now)?;
+            message.set_content(content);
+            message.algorithm = algorithm;
+            message.nonce = nonce;
+
+            let (inbox_address, _) = pda::inbox_pda(&crate::ID, &recipient);
+            require_keys_eq!(inbox_info.key(), inbox_address, ErrorCode::ConstraintSeeds);
+            let mut inbox = if is_initialized(inbox_info) {
+                load_inbox(inbox_info)?
+            } else {
+                create_pda_account(
+                    inbox_info,
+                    &[b"inbox", recipient.as_ref()],
+                    USER_INBOX_ACCOUNT_SIZE,
+                    &sender_info,
+                    &system_info,
+                )?;
+                UserInbox {
+                    owner: recipient,
+                    ..Default::default()
+                }
+            };
+            deliver(&mut inbox, &mut message)?;
+            store_account(inbox_info, &inbox)?;
+
+            create_pda_account(
+                message_info,
+                &[b"message", room.as_ref(), message.message_id.to_le_bytes().as_ref()],
+                MESSAGE_ACCOUNT_SIZE,
+                &sender_info,
+                &system_info,
+            )?;
+            store_account(message_info, &message)?;
+
+            let (stats, _) = pda::user_stats_pda(&crate::ID, &recipient);
+            require_keys_eq!(stats_info.key(), stats, ErrorCode::ConstraintSeeds);
+            update_stats(&accounts.sender_stats, UserStats::record_sent)?;
+            update_stats(stats_info, UserStats::record_received)?;
+            emit_message_sent(&message);
+        }
+
+        msg!("Batch of messages sent from {}", sender);
+        Ok(())
+    }
+
+    /// Posts one message to every member of a room. The message's recipient
+    /// is the room's own address and `group_id` its room id; members find
+    /// broadcasts by filtering on that recipient.
+    ///
+    /// This costs one account regardless of group size, where `batch_send`
+    /// costs one per recipient. In exchange there is no per-recipient
+    /// addressing: broadcasts are not indexed in inboxes, recipients' blocks
+    /// are not consulted, and receipts do not apply. The ciphertext must be
+    /// decryptable by every member, e.g. under a shared group key.
+    pub fn broadcast_to_group(
+        ctx: Context<BroadcastToGroup>,
+        room_id: u64,
+        encrypted_message: Vec<u8>,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+    ) -> Result<()> {
+        validate_ciphertext(&encrypted_message, algorithm, &nonce)?;
+        ctx.accounts.chat_room.check_message_len(encrypted_message.len())?;
+
+        let sender_balance = balance_before_send(
+            &ctx.accounts.sender,
+            Some(&ctx.accounts.message.to_account_info()),
+            None,
+            &ctx.accounts.sender_state,
+        );
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+        require!(ctx.accounts.membership.is_some(), ChatError::NotAMember);
+        let sender = &ctx.accounts.sender;
+        authorize_room_send(
+            chat_room,
+            sender_balance,
+            true,
+            None,
+            &mut ctx.accounts.sender_state,
+            now,
+        )?;
+
+        let recipient = chat_room.key();
+        fill_message(message, chat_room, sender.key(), recipient, None, now)?;
+        message.group_id = Some(room_id);
+        message.set_content(encrypted_message);
+        message.algorithm = algorithm;
+        message.nonce = nonce;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+
+        msg!("Broadcast from {} to room {}", message.sender, room_id);
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Posts one ciphertext to a small room, with the message key sealed
+    /// separately to each member. `sealed_keys[i]` is for the `i`th member
+    /// in the order members agree on off-chain, such as ascending member
+    /// key; the program only checks there is one key per member. Addressed
+    /// like a `broadcast_to_group` message.
+    pub fn send_group_message(
+        ctx: Context<BroadcastToGroup>,
+        room_id: u64,
+        ciphertext: Vec<u8>,
+        sealed_keys: Vec<[u8; SEALED_KEY_LEN]>,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+    ) -> Result<()> {
+        validate_ciphertext(&ciphertext, algorithm, &nonce)?;
+        ctx.accounts.chat_room.check_message_len(ciphertext.len())?;
+        require!(
+            sealed_keys.len() <= MAX_SEALED_KEYS
+                && sealed_keys.len() == ctx.accounts.chat_room.member_count as usize,
+            ChatError::SealedKeyCountMismatch
+        );
+
+        let sender_balance = balance_before_send(
+            &ctx.accounts.sender,
+            Some(&ctx.accounts.message.to_account_info()),
+            None,
+            &ctx.accounts.sender_state,
+        );
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+        require!(ctx.accounts.membership.is_some(), ChatError::NotAMember);
+        let sender = &ctx.accounts.sender;
+        authorize_room_send(
+            chat_room,
+            sender_balance,
+            true,
+            None,
+            &mut ctx.accounts.sender_state,
+            now,
+        )?;
+
+        let recipient = chat_room.key();
+        fill_message(message, chat_room, sender.key(), recipient, None, now)?;
+        message.group_id = Some(room_id);
+        message.set_content(ciphertext);
+        message.sealed_keys = sealed_keys;
+        message.algorithm = algorithm;
+        message.nonce = nonce;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+
+        msg!("Group message from {} to room {}", message.sender, room_id);
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Posts an automated notice such as "user joined" to the whole room,
+    /// addressed like a `broadcast_to_group` message and flagged `is_system`.
+    /// Only the room authority may post one; it bypasses pausing, membership
+    /// and rate limits, which exist to restrain members.
+    pub fn send_system_message(
+        ctx: Context<SendSystemMessage>,
+        room_id: u64,
+        encrypted_message: Vec<u8>,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+    ) -> Result<()> {
+        validate_ciphertext(&encrypted_message, algorithm, &nonce)?;
+        ctx.accounts.chat_room.check_message_len(encrypted_message.len())?;
+
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+
+        let sender = ctx.accounts.authority.key();
+        let recipient = chat_room.key();
+        fill_message(message, chat_room, sender, recipient, None, now)?;
+        message.is_system = true;
+        message.group_id = Some(room_id);
+        message.set_content(encrypted_message);
+        message.algorithm = algorithm;
+        message.nonce = nonce;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+
+        msg!("System message {} in room {}", message.message_id, room_id);
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Like `send_message`, but allocates the message account for the actual
+    /// ciphertext (see `sized_message_space`) so short messages cost less
+    /// rent. Because the account has no spare content room, the message can
+    /// later only be edited to content of at most the same length;
+    /// `migrate_message` grows it back to full size.
+    pub fn send_message_sized(
+        ctx: Context<SendMessageSized>,
+        _room_id: u64,
+        encrypted_message: Vec<u8>,
+        recipient: Pubkey,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+    ) -> Result<()> {
+        validate_ciphertext(&encrypted_message, algorithm, &nonce)?;
+        ctx.accounts.chat_room.check_message_len(encrypted_message.len())?;
+
+        let sender_balance = balance_before_send(
+            &ctx.accounts.sender,
+            Some(&ctx.accounts.message.to_account_info()),
+            Some(&ctx.accounts.inbox),
+            &ctx.accounts.sender_state,
+        );
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+        let sender = &ctx.accounts.sender;
+        authorize_room_send(
+            chat_room,
+            sender_balance,
+            ctx.accounts.membership.is_some(),
+            Some(&ctx.accounts.block),
+            &mut ctx.accounts.sender_state,
+            now,
+        )?;
+
+        fill_message(message, chat_room, sender.key(), recipient, None, now)?;
+        message.set_content(encrypted_message);
+        message.algorithm = algorithm;
+        message.nonce = nonce;
+        deliver(&mut ctx.accounts.inbox, message)?;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+        update_stats(&ctx.accounts.recipient_stats, UserStats::record_received)?;
+
+        msg!("Sized message sent from {} to {}", message.sender, message.recipient);
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Re-sends a received message to `new_recipient` as a new message from
+    /// the forwarder, recording the original's id in `forwarded_from`.
+    ///
+    /// The ciphertext is copied as-is, so forwarding only helps when
+    /// `new_recipient` can decrypt what was encrypted for the forwarder, e.g.
+    /// under a key both hold. Otherwise clients should re-encrypt and send
+    /// a fresh message instead. The original's content signature is not
+    /// carried over, since it vouches for the original sender only.
+    pub fn forward_message(ctx: Context<ForwardMessage>, new_recipient: Pubkey) -> Result<()> {
+        let forwarder_balance = balance_before_send(
+            &ctx.accounts.forwarder,
+            Some(&ctx.accounts.message.to_account_info()),
+            Some(&ctx.accounts.inbox),
+            &ctx.accounts.sender_state,
+        );
+        let original = &ctx.accounts.original;
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+        chat_room.check_message_len(original.encrypted_content.len())?;
+        let forwarder = &ctx.accounts.forwarder;
+        authorize_room_send(
+            chat_room,
+            forwarder_balance,
+            ctx.accounts.membership.is_some(),
+            Some(&ctx.accounts.block),
+            &mut ctx.accounts.sender_state,
+            now,
+        )?;
+
+        fill_message(message, chat_room, forwarder.key(), new_recipient, None, now)?;
+        message.set_content(original.encrypted_content.clone());
+        message.algorithm = original.algorithm;
+        message.nonce = original.nonce;
+        message.attachment_kind = original.attachment_kind;
+        message.attachment_cid = original.attachment_cid.clone();
+        message.forwarded_from = Some(original.message_id);
+        deliver(&mut ctx.accounts.inbox, message)?;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+        update_stats(&ctx.accounts.recipient_stats, UserStats::record_received)?;
+
+        msg!(
+            "Message {} forwarded from {} to {}",
+            original.message_id,
+            message.sender,
+            new_recipient
+        );
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Saves or overwrites the owner's draft to `recipient`. Drafts are
+    /// separate from messages: recipients never see them and they take no
+    /// message id until `promote_draft` sends them.
+    pub fn save_draft(
+        ctx: Context<SaveDraft>,
+        recipient: Pubkey,
+        encrypted_content: Vec<u8>,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+    ) -> Result<()> {
+        validate_ciphertext(&encrypted_content, algorithm, &nonce)?;
+
+        let draft = &mut ctx.accounts.draft;
+        draft.owner = ctx.accounts.owner.key();
+        draft.recipient = recipient;
+        draft.encrypted_content = encrypted_content;
+        draft.algorithm = algorithm;
+        draft.nonce = nonce;
+        draft.updated_at = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    pub fn delete_draft(ctx: Context<DeleteDraft>) -> Result<()> {
+        msg!("Draft to {} deleted", ctx.accounts.draft.recipient);
+        Ok(())
+    }
+
+    /// Sends the draft as a regular message into the room and closes it,
+    /// refunding the draft's rent to its owner.
+    pub fn promote_draft(ctx: Context<PromoteDraft>, _room_id: u64) -> Result<()> {
+        let sender_balance = balance_before_send(
+            &ctx.accounts.sender,
+            Some(&ctx.accounts.message.to_account_info()),
+            Some(&ctx.accounts.inbox),
+            &ctx.accounts.sender_state,
+        );
+        let draft = &ctx.accounts.draft;
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+        chat_room.check_message_len(draft.encrypted_content.len())?;
+        authorize_room_send(
+            chat_room,
+            sender_balance,
+            ctx.accounts.membership.is_some(),
+            Some(&ctx.accounts.block),
+            &mut ctx.accounts.sender_state,
+            now,
+        )?;
+
+        fill_message(message, chat_room, draft.owner, draft.recipient, None, now)?;
+        message.set_content(draft.encrypted_content.clone());
+        message.algorithm = draft.algorithm;
+        message.nonce = draft.nonce;
+        deliver(&mut ctx.accounts.inbox, message)?;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+        update_stats(&ctx.accounts.recipient_stats, UserStats::record_received)?;
+
+        msg!("Draft sent from {} to {}", message.sender, message.recipient);
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Creates the parent of a message too large for one account. The
+    /// ciphertext follows in `chunk_count` calls to `append_chunk`.
+    pub fn send_large_message(
+        ctx: Context<SendLargeMessage>,
+        _room_id: u64,
+        recipient: Pubkey,
+        chunk_count: u16,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+    ) -> Result<()> {
+        require!(
+            chunk_count > 0 && chunk_count <= MAX_CHUNKS,
+            ChatError::InvalidChunkCount
+        );
+        validate_cipher_params(algorithm, &nonce)?;
+
+        let sender_balance = balance_before_send(
+            &ctx.accounts.sender,
+            Some(&ctx.accounts.message.to_account_info()),
+            Some(&ctx.accounts.inbox),
+            &ctx.accounts.sender_state,
+        );
+        let message = &mut ctx.accounts.message;
+        let chat_room = &mut ctx.accounts.chat_room;
+        let now = Clock::get()?.unix_timestamp;
+        let sender = &ctx.accounts.sender;
+        authorize_room_send(
+            chat_room,
+            sender_balance,
+            ctx.accounts.membership.is_some(),
+            Some(&ctx.accounts.block),
+            &mut ctx.accounts.sender_state,
+            now,
+        )?;
+
+        fill_message(message, chat_room, sender.key(), recipient, None, now)?;
+        message.algorithm = algorithm;
+        message.nonce = nonce;
+        message.chunk_count = chunk_count;
+        deliver(&mut ctx.accounts.inbox, message)?;
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+        update_stats(&ctx.accounts.recipient_stats, UserStats::record_received)?;
+
+        msg!(
+            "Large message {} started with {} chunks",
+            message.message_id,
+            chunk_count
+        );
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Stores the next chunk of a large message. Chunks must arrive in order;
+    /// the message is complete once `chunks_received == chunk_count`.
+    pub fn append_chunk(ctx: Context<AppendChunk>, index: u16, data: Vec<u8>) -> Result<()> {
+        require!(!data.is_empty(), ChatError::EmptyMessage);
+        require!(data.len() <= MAX_CHUNK_LEN, ChatError::ChunkTooLarge);
+
+        let message = &mut ctx.accounts.message;
+        require!(
+            index == message.chunks_received && index < message.chunk_count,
+            ChatError::UnexpectedChunkIndex
+        );
+
+        let chunk = &mut ctx.accounts.chunk;
+        chunk.message = message.key();
+        chunk.index = index;
+        chunk.data = data;
+
+        message.chunks_received += 1;
+        if message.is_complete() {
+            msg!("Large message {} complete", message.message_id);
+        }
+        Ok(())
+    }
+
+    pub fn init_conversation(
+        ctx: Context<InitConversation>,
+        participant_a: Pubkey,
+        participant_b: Pubkey,
+    ) -> Result<()> {
+        require!(
+            participant_a < participant_b,
+            ChatError::UnsortedParticipants
+        );
+        let initiator = ctx.accounts.initiator.key();
+        require!(
+            initiator == participant_a || initiator == participant_b,
+            ChatError::NotAParticipant
+        );
+
+        let conversation = &mut ctx.accounts.conversation;
+        conversation.participant_a = participant_a;
+        conversation.participant_b = participant_b;
+        conversation.message_count = 0;
+
+        msg!("Conversation opened between {} and {}", participant_a, participant_b);
+        Ok(())
+    }
+
+    pub fn send_direct_message(
+        ctx: Context<SendDirectMessage>,
+        encrypted_message: Vec<u8>,
+        algorithm: u8,
+        nonce: [u8; NONCE_LEN],
+        reply_to: Option<u64>,
+    ) -> Result<()> {
+        validate_ciphertext(&encrypted_message, algorithm, &nonce)?;
+
+        let message = &mut ctx.accounts.message;
+        let conversation = &mut ctx.accounts.conversation;
+        let sender = ctx.accounts.sender.key();
+
+        let recipient = conversation
+            .peer_of(&sender)
+            .ok_or(ChatError::NotAParticipant)?;
+        let (recipient_stats, _) = pda::user_stats_pda(&crate::ID, &recipient);
+        require_keys_eq!(
+            ctx.accounts.recipient_stats.key(),
+            recipient_stats,
+            ErrorCode::ConstraintSeeds
+        );
+        if let Some(parent_id) = reply_to {
+            require!(
+                parent_id < conversation.message_count,
+                ChatError::InvalidReplyTarget
+            );
+        }
+
+        message.version = MESSAGE_VERSION;
+        message.sender = sender;
+        message.recipient = recipient;
+        message.room = conversation.key();
+        message.set_content(encrypted_message);
+        message.algorithm = algorithm;
+        message.nonce = nonce;
+        message.reply_to = reply_to;
+        message.timestamp = Clock::get()?.unix_timestamp;
+        message.message_id = conversation.next_message_id()?;
+        message.session_epoch = ctx.accounts.session.as_ref().map(|s| s.session_epoch);
+        update_stats(&ctx.accounts.sender_stats, UserStats::record_sent)?;
+        update_stats(&ctx.accounts.recipient_stats, UserStats::record_received)?;
+
+        msg!("Direct message sent from {} to {}", message.sender, message.recipient);
+        emit_message_sent(message);
+        Ok(())
+    }
+
+    /// Publishes the ephemeral keys of a forward-secret session between the
+    /// conversation's participants. Both must sign, so neither can announce
+    /// keys on the other's behalf.
+    pub fn establish_session(
+        ctx: Context<EstablishSession>,
+        ephemeral_key_a: [u8; 32],
+        ephemeral_key_b: [u8; 32],
+    ) -> Result<()> {
+        let session = &mut ctx.accounts.session;
+        session.conversation = ctx.accounts.conversation.key();
+        session.start_epoch(0, ephemeral_key_a, ephemeral_key_b, Clock::get()?.unix_timestamp);
+
+        emit!(SessionEstablished {
+            conversation: session.conversation,
+            session_epoch: session.session_epoch,
+        });
+        Ok(())
+    }
+
+    /// Replaces both ephemeral keys and advances `session_epoch`. Messages
+    /// sent under earlier epochs keep the epoch they recorded.
+    pub fn rotate_session(
+        ctx: Context<RotateSession>,
+        ephemeral_key_a: [u8; 32],
+        ephemeral_key_b: [u8; 32],
+    ) -> Result<()> {
+        let session = &mut ctx.accounts.session;
+        let epoch = session
+            .session_epoch
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
+        let now = Clock::get()?.unix_timestamp;
+        session.start_epoch(epoch, ephemeral_key_a, ephemeral_key_b, now);
+
+        emit!(SessionEstablished {
+            conversation: session.conversation,
+            session_epoch: epoch,
+        });
+        Ok(())
+    }
+
+    /// Hides or shows a chat room or conversation in the caller's chat list.
+    pub fn set_archived(ctx: Context<UpdateConversationState>, archived: bool) -> Result<()> {
+        let state = &mut ctx.accounts.conversation_state;
+        state.user = ctx.accounts.user.key();
+        state.conversation = ctx.accounts.conversation.key();
+        state.archived = archived;
+        msg!("{} set archived={} on {}", state.user, archived, state.conversation);
+        Ok(())
+    }
+
+    /// Silences notifications for a chat room or conversation.
+    pub fn set_muted(ctx: Context<UpdateConversationState>, muted: bool) -> Result<()> {
+        let state = &mut ctx.accounts.conversation_state;
+        state.user = ctx.accounts.user.key();
+        state.conversation = ctx.accounts.conversation.key();
+        state.muted = muted;
+        msg!("{} set muted={} on {}", state.user, muted, state.conversation);
+        Ok(())
+    }
+
+    /// Records how far the caller has read in a chat room or conversation,
+    /// so clients can reopen it there. The cursor only moves forward;
+    /// setting the current position again is accepted so retries succeed.
+    pub fn set_read_cursor(ctx: Context<SetReadCursor>, message_id: u64) -> Result<()> {
+        let cursor = &mut ctx.accounts.cursor;
+        require!(
+            message_id >= cursor.last_read_message_id,
+            ChatError::CursorRegression
+        );
+        cursor.last_read_message_id = message_id;
+        cursor.updated_at = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    /// Heartbeat for online status; see `PRESENCE_STALE_SECS`.
+    pub fn set_presence(ctx: Context<SetPresence>, status: u8) -> Result<()> {
+        require!(status <= PRESENCE_AWAY, ChatError::InvalidPresenceStatus);
+
+        let presence = &mut ctx.accounts.presence;
+        presence.status = status;
+        presence.last_seen = Clock::get()?.unix_timestamp;
+
+        emit!(PresenceChanged {
+            user: ctx.accounts.user.key(),
+            status,
+        });
+        Ok(())
+    }
+
+    /// Updates the caller's typing indicator in a conversation. The account
+    /// is created on first use and overwritten afterwards.
+    pub fn set_typing(ctx: Context<SetTyping>, is_typing: bool) -> Result<()> {
+        let conversation = &ctx.accounts.conversation;
+        let user = ctx.accounts.user.key();
+        require!(
+            conversation.peer_of(&user).is_some(),
+            ChatError::NotAParticipant
+        );
+
+        let typing_state = &mut ctx.accounts.typing_state;
+        typing_state.is_typing = is_typing;
+        typing_state.updated_at = Clock::get()?.unix_timestamp;
+
+        emit!(TypingChanged {
+            conversation: conversation.key(),
+            user,
+            is_typing,
+        });
+        Ok(())
+    }
+
+    pub fn register_key(
+        ctx: Context<RegisterKey>,
+        encryption_pubkey: [u8; 32],
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.key_registry;
+        registry.owner = ctx.accounts.owner.key();
+        registry.encryption_pubkey = encryption_pubkey;
+        registry.updated_at = Clock::get()?.unix_timestamp;
+
+        emit!(KeyRegistered {
+            owner: registry.owner,
+            encryption_pubkey,
+            rotated: false,
+        });
+        Ok(())
+    }
+
+    pub fn update_key(ctx: Context<UpdateKey>, encryption_pubkey: [u8; 32]) -> Result<()> {
+        let registry = &mut ctx.accounts.key_registry;
+        registry.encryption_pubkey = encryption_pubkey;
+        registry.updated_at = Clock::get()?.unix_timestamp;
+
+        emit!(KeyRegistered {
+            owner: registry.owner,
+            encryption_pubkey,
+            rotated: true,
+        });
+        Ok(())
+    }
+
+    /// Claims `name` for the signer. Names are zero-padded to 32 bytes; the
+    /// name-seeded `Username` PDA makes them globally unique.
+    pub fn register_username(ctx: Context<RegisterUsername>, name: [u8; 32]) -> Result<()> {
+        validate_username(&name)?;
+        let owner = ctx.accounts.owner.key();
+
+        // `init_if_needed` lets a taken name surface as `UsernameTaken`
+        // rather than a system program "already in use" failure.
+        let username = &mut ctx.accounts.username;
+        require!(
+            username.owner == Pubkey::default(),
+            ChatError::UsernameTaken
+        );
+        let profile = &mut ctx.accounts.profile;
+        require!(profile.name == [0; 32], ChatError::AlreadyHasUsername);
+
+        username.owner = owner;
+        username.name = name;
+        profile.owner = owner;
+        profile.name = name;
+
+        msg!("Username registered for {}", owner);
+        Ok(())
+    }
+
+    /// Frees the signer's name for anyone to claim and refunds both accounts.
+    pub fn release_username(ctx: Context<ReleaseUsername>) -> Result<()> {
+        msg!("Username released by {}", ctx.accounts.owner.key());
+        Ok(())
+    }
+
+    pub fn add_contact(ctx: Context<AddContact>, contact: Pubkey) -> Result<()> {
+        let contacts = &mut ctx.accounts.contacts;
+        contacts.owner = ctx.accounts.owner.key();
+        contacts.add(contact)?;
+        msg!("{} added contact {}", contacts.owner, contact);
+        Ok(())
+    }
+
+    pub fn remove_contact(ctx: Context<RemoveContact>, contact: Pubkey) -> Result<()> {
+        let contacts = &mut ctx.accounts.contacts;
+        contacts.remove(&contact)?;
+        msg!("{} removed contact {}", contacts.owner, contact);
+        Ok(())
+    }
+
+    pub fn block_user(ctx: Context<BlockUser>, blocked: Pubkey) -> Result<()> {
+        let block = &mut ctx.accounts.block;
+        block.blocker = ctx.accounts.blocker.key();
+        block.blocked = blocked;
+        msg!("{} blocked {}", block.blocker, block.blocked);
+        Ok(())
+    }
+
+    pub fn unblock_user(ctx: Context<UnblockUser>) -> Result<()> {
+        let block = &ctx.accounts.block;
+        msg!("{} unblocked {}", block.blocker, block.blocked);
+        Ok(())
+    }
+
+    /// Records that `muter` wants no notifications from `muted`. Unlike a
+    /// `Block`, the program never reads this; it only syncs the preference
+    /// across the muter's devices.
+    pub fn mute_user(ctx: Context<MuteUser>, muted: Pubkey) -> Result<()> {
+        let mute = &mut ctx.accounts.mute;
+        require!(mute.muter == Pubkey::default(), ChatError::AlreadyMuted);
+        mute.muter = ctx.accounts.muter.key();
+        mute.muted = muted;
+        msg!("{} muted {}", mute.muter, mute.muted);
+        Ok(())
+    }
+
+    pub fn unmute_user(ctx: Context<UnmuteUser>) -> Result<()> {
+        let mute = &ctx.accounts.mute;
+        msg!("{} unmuted {}", mute.muter, mute.muted);
+        Ok(())
+    }
+
+    pub fn init_inbox(ctx: Context<InitInbox>) -> Result<()> {
+        let inbox = &mut ctx.accounts.inbox;
+        inbox.owner = ctx.accounts.owner.key();
+        msg!("Inbox initialized for {}", inbox.owner);
+        Ok(())
+    }
+
+    /// Creates the caller's `UserStats`. Counting starts from here; earlier
+    /// messages are not back-filled.
+    pub fn init_stats(ctx: Context<InitStats>) -> Result<()> {
+        let stats = &mut ctx.accounts.stats;
+        stats.user = ctx.accounts.user.key();
+        msg!("Stats initialized for {}", stats.user);
+        Ok(())
+    }
+
+    /// Replaces a message's ciphertext within `EDIT_WINDOW_SECS` of sending.
+    /// Once the recipient has acknowledged it the message is frozen, so a
+    /// read receipt always refers to the content the recipient saw; without
+    /// a receipt there is nothing to freeze on, see `acknowledge_message`. Room
+    /// messages are held to the room's `max_message_len`, as when sent.
+    pub fn edit_message(
+        ctx: Context<EditMessage>,
+        new_encrypted_content: Vec<u8>,
+    ) -> Result<()> {
+        require!(
+            new_encrypted_content.len() <= MAX_MESSAGE_LEN,
+            ChatError::MessageTooLong
+        );
+        if let Some(chat_room) = load_chat_room(&ctx.accounts.room)? {
+            chat_room.check_message_len(new_encrypted_content.len())?;
+        }
+        let capacity = message_content_capacity(ctx.accounts.message.to_account_info().data_len());
+        require!(
+            new_encrypted_content.len() <= capacity,
+            ChatError::MessageTooLong
+        );
+
+        let message = &mut ctx.accounts.message;
+        let now = Clock::get()?.unix_timestamp;
+        require!(message.within_edit_window(now), ChatError::EditWindowExpired);
+        require!(message.read_at.is_none(), ChatError::AlreadyRead);
+        message.record_edit(new_encrypted_content, now);
+
+        msg!("Message {} edited", message.message_id);
+        Ok(())
+    }
+
+    /// Redirects a misaddressed room message before its recipient has seen
+    /// it, moving its inbox entry from the old recipient to the new one. The
+    /// old recipient's `recipient_seq` sequence is left with a gap. Tips
+    /// already escrowed for the message stay claimable by the old recipient.
+    pub fn reassign_recipient(ctx: Context<ReassignRecipient>, new_recipient: Pubkey) -> Result<()> {
+        let message = &mut ctx.accounts.message;
+        require!(
+            message.delivered_at.is_none() && message.read_at.is_none(),
+            ChatError::AlreadyDelivered
+        );
+        require!(!is_initialized(&ctx.accounts.block), ChatError::SenderBlocked);
+        if new_recipient == message.recipient {
+            return Ok(());
+        }
+
+        let old_inbox_info = ctx.accounts.old_inbox.to_account_info();
+        if is_initialized(&old_inbox_info) {
+            let mut old_inbox = load_inbox(&old_inbox_info)?;
+            old_inbox.remove(message.room, message.message_id);
+            store_account(&old_inbox_info, &old_inbox)?;
+        }
+        let new_inbox = &mut ctx.accounts.new_inbox;
+        new_inbox.owner = new_recipient;
+        message.recipient_seq = new_inbox.push(message.room, message.message_id)?;
+        update_stats(&ctx.accounts.old_recipient_stats, |stats| {
+            stats.record_unreceived();
+            Ok(())
+        })?;
+        update_stats(&ctx.accounts.new_recipient_stats, UserStats::record_received)?;
+
+        msg!(
+            "Message {} reassigned from {} to {}",
+            message.message_id,
+            message.recipient,
+            new_recipient
+        );
+        message.recipient = new_recipient;
+        Ok(())
+    }
+
+    /// First step of the sent -> delivered -> read receipt sequence.
+    pub fn mark_delivered(ctx: Context<MarkDelivered>) -> Result<()> {
+        let message = &mut ctx.accounts.message;
+        require!(message.delivered_at.is_none(), ChatError::AlreadyDelivered);
+
+        message.delivered_at = Some(Clock::get()?.unix_timestamp);
+
+        emit!(MessageDelivered {
+            message_id: message.message_id,
+            recipient: message.recipient,
+        });
+        Ok(())
+    }
+
+    /// Turns the caller's read receipts on or off; see `acknowledge_message`.
+    pub fn set_read_receipts(ctx: Context<SetReadReceipts>, enabled: bool) -> Result<()> {
+        ctx.accounts.settings.read_receipts_enabled = enabled;
+        msg!("{} set read_receipts_enabled={}", ctx.accounts.user.key(), enabled);
+        Ok(())
+    }
+
+    /// Marks a delivered message as read. With read receipts disabled in the
+    /// recipient's `UserSettings`, only the recipient's own unread counts move:
+    /// `read_at` stays unset and no `MessageRead` is emitted, since anything
+    /// stored on the message would be visible to the sender. A `ReadMarker`,
+    /// paid for by the recipient, stands in for `read_at` so a repeated
+    /// acknowledge still fails with `AlreadyRead`.
+    ///
+    /// Without `read_at` the sender can keep editing such a message for the
+    /// rest of `EDIT_WINDOW_SECS`, as freezing it would reveal the read. The
+    /// recipient can still tell from `prev_content_hashes` that it changed.
+    ///
+    /// A `view_once` message is closed here, refunding its sender, so its
+    /// ciphertext is gone as soon as it has been read.
+    pub fn acknowledge_message(ctx: Context<AcknowledgeMessage>) -> Result<()> {
+        let receipts = read_receipts_enabled(&ctx.accounts.settings)?;
+        let message = &mut ctx.accounts.message;
+        require!(message.delivered_at.is_some(), ChatError::NotDelivered);
+        let marker = ctx.accounts.read_marker.to_account_info();
+        require!(
+            message.read_at.is_none() && !is_initialized(&marker),
+            ChatError::AlreadyRead
+        );
+
+        if receipts {
+            message.read_at = Some(Clock::get()?.unix_timestamp);
+        } else {
+            let recipient = ctx.accounts.recipient.key();
+            let message_key = message.key();
+            create_pda_account(
+                &marker,
+                &[b"read", recipient.as_ref(), message_key.as_ref()],
+                READ_MARKER_ACCOUNT_SIZE,
+                &ctx.accounts.recipient.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+            )?;
+            store_account(&marker, &ReadMarker {})?;
+        }
+
+        // Direct messages are not indexed, so their recipient may have no inbox.
+        let inbox_info = ctx.accounts.inbox.to_account_info();
+        if is_initialized(&inbox_info) {
+            let mut inbox = load_inbox(&inbox_info)?;
+            inbox.unread_count = inbox.unread_count.saturating_sub(1);
+            store_account(&inbox_info, &inbox)?;
+        }
+        update_stats(&ctx.accounts.recipient_stats, |stats| {
+            stats.record_read();
+            Ok(())
+        })?;
+
+        if receipts {
+            emit!(MessageRead {
+                message_id: message.message_id,
+                reader: message.recipient,
+            });
+        }
+        if message.view_once {
+            message.close(ctx.accounts.sender.to_account_info())?;
+        }
+        Ok(())
+    }
+
+    /// Closes the caller's inbox and refunds its rent.
+    ///
+    /// Unread messages do not block closing: they live in their own accounts
+    /// and stay readable, only the index is lost. A later message to the
+    /// owner re-creates the inbox, starting from an empty index.
+    pub fn close_inbox(ctx: Context<CloseInbox>) -> Result<()> {
+        emit!(InboxClosed {
+            owner: ctx.accounts.owner.key(),
+        });
+        Ok(())
+    }
+
+    /// Clears the inbox badge count without acknowledging each message.
+    pub fn mark_all_read(ctx: Context<MarkAllRead>) -> Result<()> {
+        let inbox_info = ctx.accounts.inbox.to_account_info();
+        let mut inbox = load_inbox(&inbox_info)?;
+        inbox.unread_count = 0;
+        inbox.last_read_at = Some(Clock::get()?.unix_timestamp);
+        store_account(&inbox_info, &inbox)?;
+
+        msg!("Inbox of {} marked read", inbox.owner);
+        Ok(())
+    }
+
+    /// Retracts a recently sent message, closing it and refunding rent.
+    pub fn unsend_message(ctx: Context<UnsendMessage>) -> Result<()> {
+        let message = &ctx.accounts.message;
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            message.within_recall_window(now),
+            ChatError::RecallWindowExpired
+        );
+        release_reply(message, ctx.accounts.parent.as_ref())?;
+
+        emit!(MessageRecalled {
+            message_id: message.message_id,
+            room: message.room,
+        });
+        Ok(())
+    }
+
+    /// Deletes a message for everyone by closing its account. Participants
+    /// who only want it gone from their own view use `hide_message`.
+    pub fn delete_message(ctx: Context<DeleteMessage>) -> Result<()> {
+        let message = &ctx.accounts.message;
+        release_reply(message, ctx.accounts.parent.as_ref())?;
+        msg!("Message {} deleted", message.message_id);
+        Ok(())
+    }
+
+    /// Deletes a message for the caller only: clients skip messages for
+    /// which the viewer has a `HiddenMessage`, while the other participant
+    /// and the message itself are unaffected.
+    pub fn hide_message(ctx: Context<HideMessage>) -> Result<()> {
+        let hidden = &mut ctx.accounts.hidden;
+        hidden.user = ctx.accounts.user.key();
+        hidden.message = ctx.accounts.message.key();
+        hidden.hidden_at = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    /// Reverses `hide_message`. Works after the message itself is deleted,
+    /// so the rent of a stale record is never stranded.
+    pub fn unhide_message(ctx: Context<UnhideMessage>) -> Result<()> {
+        msg!("{} unhid message {}", ctx.accounts.user.key(), ctx.accounts.hidden.message);
+        Ok(())
+    }
+
+    /// Bookmarks a message for the caller. Stars are private to each user,
+    /// unlike pins, which are shared by the whole room; only the total is
+    /// mirrored on the message.
+    pub fn star_message(ctx: Context<StarMessage>) -> Result<()> {
+        let message = &mut ctx.accounts.message;
+        message.star_count = message.star_count.saturating_add(1);
+
+        let starred = &mut ctx.accounts.starred;
+        starred.user = ctx.accounts.user.key();
+        starred.message = message.key();
+        starred.starred_at = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    /// Removes the caller's star. The message may already be deleted, in
+    /// which case only the `StarredMessage` is closed.
+    pub fn unstar_message(ctx: Context<UnstarMessage>) -> Result<()> {
+        let message_info = ctx.accounts.message.to_account_info();
+        if is_initialized(&message_info) {
+            let mut message =
+                Message::try_deserialize(&mut &message_info.try_borrow_data()?[..])?;
+            message.star_count = message.star_count.saturating_sub(1);
+            store_account(&message_info, &message)?;
+        }
+        msg!("{} unstarred message {}", ctx.accounts.user.key(), ctx.accounts.starred.message);
+        Ok(())
+    }
+
+    /// Flags a message for the room's moderators. Each user may report a
+    /// given message once; the `Report` PDA's existence enforces that.
+    pub fn report_message(ctx: Context<ReportMessage>, reason_code: u8) -> Result<()> {
+        require!(reason_code < REPORT_REASONS, ChatError::InvalidReasonCode);
+        let message = &mut ctx.accounts.message;
+        let reporter = ctx.accounts.reporter.key();
+        require!(reporter != message.sender, ChatError::CannotReportOwn);
+
+        message.report_count = message.report_count.saturating_add(1);
+
+        let report = &mut ctx.accounts.report;
+        report.message = message.key();
+        report.reporter = reporter;
+        report.reason_code = reason_code;
+        report.reported_at = Clock::get()?.unix_timestamp;
+
+        msg!("Message {} reported by {}", message.message_id, reporter);
+        Ok(())
+    }
+
+    pub fn add_reaction(ctx: Context<AddReaction>, reaction_index: u8) -> Result<()> {
+        let reactions = &mut ctx.accounts.reactions;
+        reactions.message = ctx.accounts.message.key();
+        let slot = reactions.slot_mut(reaction_index)?;
+        *slot = slot.saturating_add(1);
+        let count = *slot;
+
+        let message = &mut ctx.accounts.message;
+        message.total_reactions = message.total_reactions.saturating_add(1);
+
+        emit!(ReactionChanged {
+            message: reactions.message,
+            reaction_index,
+            count,
+        });
+        Ok(())
+    }
+
+    pub fn remove_reaction(ctx: Context<RemoveReaction>, reaction_index: u8) -> Result<()> {
+        let reactions = &mut ctx.accounts.reactions;
+        let slot = reactions.slot_mut(reaction_index)?;
+        let removed = *slot > 0;
+        *slot = slot.saturating_sub(1);
+        let count = *slot;
+
+        if removed {
+            let message = &mut ctx.accounts.message;
+            message.total_reactions = message.total_reactions.saturating_sub(1);
+        }
+
+        emit!(ReactionChanged {
+            message: reactions.message,
+            reaction_index,
+            count,
+        });
+        Ok(())
+    }
+
+    /// Rewrites a message stored in an older layout at `MESSAGE_VERSION`,
+    /// growing the account if needed; the migrator pays any extra rent.
+    /// Only the sender or the authority of the message's room may migrate.
+    /// Messages already at the current version are left unchanged.
+    pub fn migrate_message(ctx: Context<MigrateMessage>) -> Result<()> {
+        let info = ctx.accounts.message.to_account_info();
+        let message = read_versioned_message(&info.try_borrow_data()?)?;
+
+        let migrator = ctx.accounts.migrator.key();
+        let room_authority = ctx
+            .accounts
+            .chat_room
+            .as_ref()
+            .is_some_and(|room| room.key() == message.room && room.authority == migrator);
+        require!(
+            migrator == message.sender || room_authority,
+            ChatError::Unauthorized
+        );
+
+        if info.data_len() < MESSAGE_ACCOUNT_SIZE {
+            let shortfall = Rent::get()?
+                .minimum_balance(MESSAGE_ACCOUNT_SIZE)
+                .saturating_sub(info.lamports());
+            if shortfall > 0 {
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.migrator.to_account_info(),
+                            to: info.clone(),
+                        },
+                    ),
+                    shortfall,
+                )?;
+            }
+            info.resize(MESSAGE_ACCOUNT_SIZE)?;
+        }
+        store_account(&info, &message)?;
+
+        msg!(
+            "Message {} migrated to version {}",
+            message.message_id,
+            MESSAGE_VERSION
+        );
+        Ok(())
+    }
+
+    /// Permissionless cleanup of an expired message; rent goes back to its sender.
+    /// Returns, and emits as `MessagesReaped`, the rent the close reclaims.
+    pub fn reap_expired_message(ctx: Context<ReapExpiredMessage>) -> Result<ReapResult> {
+        let message = &ctx.accounts.message;
+        let now = Clock::get()?.unix_timestamp;
+        require!(message.is_expired(now), ChatError::NotExpired);
+        release_reply(message, ctx.accounts.parent.as_ref())?;
+
+        msg!("Expired message {} reaped", message.message_id);
+        let result = ReapResult {
+            closed_count: 1,
+            lamports_reclaimed: message.to_account_info().lamports(),
+        };
+        emit!(MessagesReaped {
+            closed_count: result.closed_count,
+            lamports_reclaimed: result.lamports_reclaimed,
+        });
+        Ok(result)
+    }
+
+    /// Permissionless bulk form of `reap_expired_message`. `remaining_accounts`
+    /// holds, per message, the message account followed by its sender, who
+    /// receives the rent. Messages that have not expired are skipped rather
+    /// than failing the batch. Returns, and emits as `MessagesReaped`, the
+    /// number of messages closed and the rent they held.
+    pub fn reap_expired_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ReapExpiredBatch>,
+    ) -> Result<ReapResult> {
+        let pairs = ctx.remaining_accounts;
+        require!(pairs.len() % 2 == 0, ErrorCode::AccountNotEnoughKeys);
+        require!(pairs.len() / 2 <= MAX_REAP_BATCH, ChatError::BatchTooLarge);
+
+        let now = Clock::get()?.unix_timestamp;
+        let mut result = ReapResult::default();
+        for pair in pairs.chunks_exact(2) {
+            let [message_info, sender_info] = pair else {
+                unreachable!("chunks_exact(2) yields slices of two");
+            };
+            // Checks the owner and discriminator, so only genuine messages close
+            let message = util::load_message_checked(message_info)?;
+            require_keys_eq!(sender_info.key(), message.sender, ErrorCode::ConstraintHasOne);
+            if !message.is_expired(now) {
+                continue;
+            }
+
+            msg!("Expired message {} reaped", message.message_id);
+            result.closed_count += 1;
+            result.lamports_reclaimed += message_info.lamports();
+            message.close(sender_info.clone())?;
+        }
+        emit!(MessagesReaped {
+            closed_count: result.closed_count,
+            lamports_reclaimed: result.lamports_reclaimed,
+        });
+        Ok(result)
+    }
+
+    /// Authority sweep that closes messages `start_id..start_id + count`,
+    /// refunding each sender. `remaining_accounts` holds, per id in order,
+    /// the message PDA followed by its sender; ids whose message is already
+    /// gone are skipped, and any account may stand in for their sender.
+    /// Returns the next id to purge, so clients wipe a room by calling again
+    /// until it reaches `message_count`. Chunks, reactions and tip escrows
+    /// of purged messages are not touched.
+    pub fn purge_messages<'info>(
+        ctx: Context<'_, '_, 'info, 'info, PurgeMessages>,
+        start_id: u64,
+        count: u16,
+    ) -> Result<u64> {
+        require!(count <= MAX_PURGE_BATCH, ChatError::BatchTooLarge);
+        let pairs = ctx.remaining_accounts;
+        require!(pairs.len() == usize::from(count) * 2, ErrorCode::AccountNotEnoughKeys);
+        let room = ctx.accounts.chat_room.key();
+        let end = start_id
+            .checked_add(u64::from(count))
+            .filter(|end| *end <= ctx.accounts.chat_room.message_count)
+            .ok_or(ChatError::InvalidPurgeRange)?;
+
+        for (message_id, pair) in (start_id..end).zip(pairs.chunks_exact(2)) {
+            let [message_info, sender_info] = pair else {
+                unreachable!("chunks_exact(2) yields slices of two");
+            };
+            let (address, _) = pda::message_pda(&crate::ID, &room, message_id);
+            require_keys_eq!(message_info.key(), address, ErrorCode::ConstraintSeeds);
+            if !is_initialized(message_info) {
+                continue;
+            }
+            let message = util::load_message_checked(message_info)?;
+            require_keys_eq!(sender_info.key(), message.sender, ErrorCode::ConstraintHasOne);
+            message.close(sender_info.clone())?;
+        }
+
+        msg!("Purged messages {}..{} of room {}", start_id, end, room);
+        Ok(end)
+    }
+
+    /// Read-only summary of a message, for use through `simulateTransaction`
+    /// (Anchor's `.view()`) rather than a fee-paying transaction. Anchor
+    /// writes the returned `MessageView` with `set_return_data` and
+    /// publishes its layout in the IDL, so clients decode it without
+    /// hand-parsing the account.
+    pub fn get_message(ctx: Context<GetMessage>, _message_id: u64) -> Result<MessageView> {
+        let message = &ctx.accounts.message;
+        Ok(MessageView {
+            sender: message.sender,
+            recipient: message.recipient,
+            timestamp: message.timestamp,
+            message_id: message.message_id,
+            content_len: message.encrypted_content.len() as u32,
+        })
+    }
+
+    /// Lists `user`'s recent messages from their inbox, oldest first, for
+    /// use through simulation like `get_message`. Entries carry the room as
+    /// well as the id since ids are only unique per room. A user without an
+    /// inbox has no messages.
+    pub fn get_messages_for_user(
+        ctx: Context<GetMessages>,
+        user: Pubkey,
+    ) -> Result<Vec<InboxEntry>> {
+        let inbox_info = ctx.accounts.inbox.to_account_info();
+        if !is_initialized(&inbox_info) {
+            return Ok(Vec::new());
+        }
+        let inbox = load_inbox(&inbox_info)?;
+        require_keys_eq!(inbox.owner, user, ChatError::Unauthorized);
+        Ok(inbox.recent_messages)
+    }
+}
+
+/// Shared body of `send_message` and the tipping variants that wrap its accounts.
+/// `extra_rent` is what the caller's own accounts, such as a tip escrow,
+/// have already taken from the payer.
+#[allow(clippy::too_many_arguments)]
+fn process_send_message(
+    accounts: &mut SendMessage,
+    extra_rent: u64,
+    encrypted_message: Vec<u8>,
+    recipient: Pubkey,
+    algorithm: u8,
+    nonce: [u8; NONCE_LEN],
+    reply_to: Option<u64>,
+    expires_at: Option<i64>,
+    content_signature: [u8; 64],
+    attachment: Option<Attachment>,
+    priority: u8,
+) -> Result<()> {
+    validate_ciphertext(&encrypted_message, algorithm, &nonce)?;
+    accounts.chat_room.check_message_len(encrypted_message.len())?;
+    require!(priority <= PRIORITY_URGENT, ChatError::InvalidPriority);
+    if let Some(attachment) = &attachment {
+        attachment.validate()?;
+    }
+    if let Some(key_registry) = &accounts.key_registry {
+        let instructions = accounts
+            .instructions
+            .as_ref()
+            .ok_or(ChatError::SignatureVerificationFailed)?;
+        verify_content_signature(
+            instructions,
+            &key_registry.encryption_pubkey,
+            &keccak::hash(&encrypted_message).to_bytes(),
+            &content_signature,
+        )?;
+    }
+
+    let sender_balance = if accounts.payer.key() == accounts.sender.key() {
+        balance_before_send(
+            &accounts.sender,
+            Some(&accounts.message.to_account_info()),
+            Some(&accounts.inbox),
+            &accounts.sender_state,
+        )
+        .saturating_add(extra_rent)
+    } else {
+        accounts.sender.lamports()
+    };
+    let message = &mut accounts.message;
+    let chat_room = &mut accounts.chat_room;
+    let now = Clock::get()?.unix_timestamp;
+    authorize_room_send(
+        chat_room,
+        sender_balance,
+        accounts.membership.is_some(),
+        Some(&accounts.block),
+        &mut accounts.sender_state,
+        now,
+    )?;
+
+    if let Some(parent_id) = reply_to {
+        require!(
+            parent_id < chat_room.message_count,
+            ChatError::InvalidReplyTarget
+        );
+        let parent = accounts
+            .parent
+            .as_mut()
+            .filter(|parent| parent.message_id == parent_id)
+            .ok_or(ChatError::InvalidReplyTarget)?;
+        parent.reply_count = parent.reply_count.saturating_add(1);
+    }
+    if let Some(deadline) = expires_at {
+        validate_future_deadline(deadline, now)?;
+    }
+
+    let sender = accounts.sender.key();
+    fill_message(message, chat_room, sender, recipient, expires_at, now)?;
+    message.set_content(encrypted_message);
+    message.algorithm = algorithm;
+    message.nonce = nonce;
+    message.reply_to = reply_to;
+    message.content_signature = content_signature;
+    message.priority = priority;
+    if let Some(attachment) = attachment {
+        message.attachment_kind = attachment.kind;
+        message.attachment_cid = attachment.cid;
+    }
+    message.signature_verified = accounts.key_registry.is_some();
+    deliver(&mut accounts.inbox, message)?;
+    update_stats(&accounts.sender_stats, UserStats::record_sent)?;
+    update_stats(&accounts.recipient_stats, UserStats::record_received)?;
+
+    msg!("Message sent from {} to {}", message.sender, message.recipient);
+    emit_message_sent(message);
+    Ok(())
+}
+
+/// Verifies a detached signature over the message's `content_hash` using
+/// the native ed25519 program. The program cannot check signatures itself,
+/// so the client places an ed25519 verify instruction immediately before
+/// this one; if that instruction is present the runtime has already
+/// verified it, and here we only confirm it covered this key, signature and
+/// hash. Signing the 32-byte hash rather than the ciphertext keeps the
+/// verify instruction small regardless of message length.
+fn verify_content_signature(
+    instructions: &AccountInfo,
+    pubkey: &[u8; 32],
+    content_hash: &[u8; 32],
+    signature: &[u8; 64],
+) -> Result<()> {
+    let current = load_current_index_checked(instructions)?;
+    require!(current > 0, ChatError::SignatureVerificationFailed);
+    let ix = load_instruction_at_checked(usize::from(current - 1), instructions)?;
+    require!(
+        ix.program_id == ed25519_program::ID,
+        ChatError::SignatureVerificationFailed
+    );
+    require!(
+        ed25519_ix_covers(&ix.data, pubkey, content_hash, signature),
+        ChatError::SignatureVerificationFailed
+    );
+    Ok(())
+}
+
+/// Whether ed25519 program instruction data holds exactly one signature,
+/// stored inline, over `content` by `pubkey`.
+fn ed25519_ix_covers(
+    data: &[u8],
+    pubkey: &[u8; 32],
+    content: &[u8],
+    signature: &[u8; 64],
+) -> bool {
+    // Header: count (u8), padding (u8), then seven u16 offsets per signature.
+    const HEADER_LEN: usize = 16;
+    if data.len() < HEADER_LEN || data[0] != 1 {
+        return false;
+    }
+    let read = |at: usize| usize::from(u16::from_le_bytes([data[at], data[at + 1]]));
+    let inline = u16::MAX as usize;
+    let (sig_at, sig_ix) = (read(2), read(4));
+    let (key_at, key_ix) = (read(6), read(8));
+    let (msg_at, msg_len, msg_ix) = (read(10), read(12), read(14));
+    if sig_ix != inline || key_ix != inline || msg_ix != inline {
+        return false;
+    }
+    data.get(sig_at..sig_at + 64) == Some(&signature[..])
+        && data.get(key_at..key_at + 32) == Some(&pubkey[..])
+        && data.get(msg_at..msg_at + msg_len) == Some(content)
+}
+
+/// Checks shared by every instruction that stores new ciphertext.
+fn validate_ciphertext(
+    encrypted_message: &[u8],
+    algorithm: u8,
+    nonce: &[u8; NONCE_LEN],
+) -> Result<()> {
+    require!(!encrypted_message.is_empty(), ChatError::EmptyMessage);
+    require!(
+        encrypted_message.len() <= MAX_MESSAGE_LEN,
+        ChatError::MessageTooLong
+    );
+    validate_cipher_params(algorithm, nonce)
+}
+
+fn validate_cipher_params(algorithm: u8, nonce: &[u8; NONCE_LEN]) -> Result<()> {
+    require!(
+        SUPPORTED_ALGORITHMS.contains(&algorithm),
+        ChatError::UnknownAlgorithm
+    );
+    require!(nonce.iter().any(|b| *b != 0), ChatError::InvalidNonce);
+    Ok(())
+}
+
+/// A username is 1-32 bytes of `[a-z0-9_]`, zero-padded on the right.
+fn validate_username(name: &[u8; 32]) -> Result<()> {
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    let (chars, padding) = name.split_at(len);
+    require!(
+        !chars.is_empty()
+            && chars
+                .iter()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || *b == b'_')
+            && padding.iter().all(|b| *b == 0),
+        ChatError::InvalidUsername
+    );
+    Ok(())
+}
+
+/// Sanity-checks a client-supplied deadline against the cluster clock.
+fn validate_future_deadline(deadline: i64, now: i64) -> Result<()> {
+    require!(deadline >= now, ChatError::DeadlineInPast);
+    require!(
+        deadline - now <= MAX_DEADLINE_SECS,
+        ChatError::DeadlineTooFar
+    );
+    Ok(())
+}
+
+/// A room TTL must fit within the deadline bound senders are held to.
+fn validate_room_ttl(default_ttl_secs: Option<u32>) -> Result<()> {
+    require!(
+        !default_ttl_secs.is_some_and(|ttl| i64::from(ttl) > MAX_DEADLINE_SECS),
+        ChatError::ConfigTooLarge
+    );
+    Ok(())
+}
+
+/// Room-level checks every send into a `ChatRoom` must pass.
+/// `sender_balance` is the sending wallet's lamports before the send paid
+/// any rent; see `balance_before_send`. `block` is the
+/// recipient's `Block` PDA for the sender, or `None` for group broadcasts,
+/// which have no single recipient, and for `batch_send`, which checks each
+/// recipient's itself. A send that passes restarts slow mode.
+fn authorize_room_send(
+    chat_room: &mut ChatRoom,
+    sender_balance: u64,
+    is_member: bool,
+    block: Option<&AccountInfo>,
+    sender_state: &mut SenderState,
+    now: i64,
+) -> Result<()> {
+    require!(!chat_room.paused, ChatError::RoomPaused);
+    require!(
+        sender_balance >= chat_room.min_sender_balance,
+        ChatError::InsufficientBalance
+    );
+    require!(!chat_room.in_slow_mode(now), ChatError::SlowMode);
+    require!(!chat_room.gated || is_member, ChatError::NotAMember);
+    require!(!block.is_some_and(is_initialized), ChatError::SenderBlocked);
+    sender_state.record_send(
+        now,
+        chat_room.rate_limit_window_secs,
+        chat_room.rate_limit_max_messages,
+        chat_room.rate_limit_min_interval_secs,
+    )?;
+    chat_room.last_room_message_at = now;
+    Ok(())
+}
+
+/// The sender's lamports as they were before this send, when the sender is
+/// its payer. Anchor funds `init` accounts before the handler runs, so
+/// the rent of `message` is added back, and that of `inbox` and
+/// `sender_state` when this send created them: both stay blank until the
+/// send fills them in.
+fn balance_before_send(
+    sender: &AccountInfo,
+    message: Option<&AccountInfo>,
+    inbox: Option<&Account<UserInbox>>,
+    sender_state: &Account<SenderState>,
+) -> u64 {
+    let mut balance = sender.lamports();
+    balance = balance.saturating_add(message.map_or(0, |message| message.lamports()));
+    if let Some(inbox) = inbox.filter(|inbox| inbox.owner == Pubkey::default()) {
+        balance = balance.saturating_add(inbox.to_account_info().lamports());
+    }
+    if sender_state.last_sent == 0 {
+        balance = balance.saturating_add(sender_state.to_account_info().lamports());
+    }
+    balance
+}
+
+/// Fills the fields every message sent into `chat_room` shares and takes
+/// the room's next message id. `expires_at` is the sender's requested
+/// deadline, if any; the room's TTL applies otherwise. Callers set the
+/// content and whatever else their variant adds.
+fn fill_message(
+    message: &mut Message,
+    chat_room: &mut Account<ChatRoom>,
+    sender: Pubkey,
+    recipient: Pubkey,
+    expires_at: Option<i64>,
+    now: i64,
+) -> Result<()> {
+    message.version = MESSAGE_VERSION;
+    message.sender = sender;
+    message.recipient = recipient;
+    message.room = chat_room.key();
+    message.security_epoch = chat_room.security_epoch;
+    message.expires_at = chat_room.message_expiry(now, expires_at);
+    message.timestamp = now;
+    message.message_id = chat_room.record_message(now)?;
+    Ok(())
+}
+
+/// Indexes a filled message in its recipient's inbox.
+fn deliver(inbox: &mut UserInbox, message: &mut Message) -> Result<()> {
+    inbox.owner = message.recipient;
+    message.recipient_seq = inbox.push(message.room, message.message_id)?;
+    Ok(())
+}
+
+fn emit_message_sent(message: &Message) {
+    emit!(MessageSent {
+        message_id: message.message_id,
+        room: message.room,
+        sender: message.sender,
+        recipient: message.recipient,
+        timestamp: message.timestamp,
+        reply_to: message.reply_to,
+        attachment_kind: message.attachment_kind,
+        priority: message.priority,
+        content_hash: message.content_hash,
+    });
+}
+
+/// Checks that `signer` holds at least `min_role` in the room. The room
+/// authority passes every check; anyone else needs their `Membership`.
+fn require_role(
+    chat_room: &ChatRoom,
+    signer: &Pubkey,
+    membership: Option<&Membership>,
+    min_role: u8,
+) -> Result<()> {
+    if *signer == chat_room.authority {
+        return Ok(());
+    }
+    require!(
+        membership.is_some_and(|m| m.role >= min_role),
+        ChatError::Unauthorized
+    );
+    Ok(())
+}
+
+/// Reads a `Message` in any layout `migrate_message` supports, returning it
+/// at the current version. Version 1 lacked the `version` byte, so it is
+/// spliced in; every later version only appended fields, which read as
+/// zero from the padding of a shorter account and are then filled in.
+fn read_versioned_message(data: &[u8]) -> Result<Message> {
+    require!(
+        data.starts_with(Message::DISCRIMINATOR),
+        ErrorCode::AccountDiscriminatorMismatch
+    );
+    let mut data = data.to_vec();
+    match data.len() {
+        // `send_message_sized` accounts, which always carry the version byte
+        len if (MIN_SIZED_MESSAGE_ACCOUNT_SIZE..MESSAGE_V1_ACCOUNT_SIZE).contains(&len) => {}
+        MESSAGE_V1_ACCOUNT_SIZE => data.insert(8, 2),
+        MESSAGE_V2_ACCOUNT_SIZE
+        | MESSAGE_V3_ACCOUNT_SIZE
+        | MESSAGE_V4_ACCOUNT_SIZE
+        | MESSAGE_V5_ACCOUNT_SIZE
+        | MESSAGE_V6_ACCOUNT_SIZE
+        | MESSAGE_V7_ACCOUNT_SIZE
+        | MESSAGE_V8_ACCOUNT_SIZE
+        | MESSAGE_V9_ACCOUNT_SIZE
+        | MESSAGE_V10_ACCOUNT_SIZE
+        | MESSAGE_V11_ACCOUNT_SIZE
+        | MESSAGE_V12_ACCOUNT_SIZE
+        | MESSAGE_ACCOUNT_SIZE => {}
+        _ => return err!(ChatError::UnknownAccountVersion),
+    }
+    data.resize(MESSAGE_ACCOUNT_SIZE, 0);
+
+    let mut message = Message::try_deserialize(&mut &data[..])?;
+    if message.version < 3 {
+        message.content_hash = keccak::hash(&message.encrypted_content).to_bytes();
+    }
+    message.version = MESSAGE_VERSION;
+    Ok(message)
+}
+
+/// Creates the `SentMarker` for `client_nonce`, failing with `DuplicateSend`
+/// if an earlier send already did. Anchor's `init` cannot be used because a
+/// failed create surfaces as a system program error rather than a `ChatError`.
+fn claim_client_nonce<'info>(
+    accounts: &SendMessage<'info>,
+    client_nonce: &[u8; CLIENT_NONCE_LEN],
+) -> Result<()> {
+    let marker = accounts
+        .sent_marker
+        .as_ref()
+        .ok_or(ChatError::MissingSentMarker)?
+        .to_account_info();
+    require!(!is_initialized(&marker), ChatError::DuplicateSend);
+    create_pda_account(
+        &marker,
+        &[b"sent", accounts.sender.key().as_ref(), client_nonce.as_ref()],
+        SENT_MARKER_ACCOUNT_SIZE,
+        &accounts.payer.to_account_info(),
+        &accounts.system_program.to_account_info(),
+    )?;
+    store_account(&marker, &SentMarker {})
+}
+
+/// Takes a closing reply back off its parent's `reply_count`. `parent` must
+/// be passed for replies; a parent that is already gone is skipped.
+fn release_reply(message: &Message, parent: Option<&UncheckedAccount>) -> Result<()> {
+    if message.reply_to.is_none() {
+        return Ok(());
+    }
+    let parent_info = parent.ok_or(ChatError::InvalidReplyTarget)?.to_account_info();
+    if is_initialized(&parent_info) {
+        let mut parent = Message::try_deserialize(&mut &parent_info.try_borrow_data()?[..])?;
+        parent.reply_count = parent.reply_count.saturating_sub(1);
+        store_account(&parent_info, &parent)?;
+    }
+    Ok(())
+}
+
+/// Applies `update` to the `UserStats` at `info` if its user has created one.
+fn update_stats(
+    info: &AccountInfo,
+    update: impl FnOnce(&mut UserStats) -> Result<()>,
+) -> Result<()> {
+    if !is_initialized(info) {
+        return Ok(());
+    }
+    let mut stats = UserStats::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+    update(&mut stats)?;
+    store_account(info, &stats)
+}
+
+/// Read receipts are on unless the user's `UserSettings` turns them off.
+fn read_receipts_enabled(info: &AccountInfo) -> Result<bool> {
+    if !is_initialized(info) {
+        return Ok(true);
+    }
+    let settings = UserSettings::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+    Ok(settings.read_receipts_enabled)
+}
+
+/// Whether a PDA has been created by this program. Used for marker accounts
+/// such as `Block`, whose mere existence carries the meaning.
+fn is_initialized(info: &AccountInfo) -> bool {
+    *info.owner == crate::ID && !info.data_is_empty()
+}
+
+/// Reads a `UserInbox` that the account resolver was not asked to require.
+fn load_inbox(info: &AccountInfo) -> Result<UserInbox> {
+    require!(is_initialized(info), ChatError::NoInbox);
+    let data = info.try_borrow_data()?;
+    UserInbox::try_deserialize(&mut &data[..])
+}
+
+/// Loads `info` as a `ChatRoom` if it is one. Any other account, including a
+/// `Conversation` or a room that has since been closed, is `None`.
+fn load_chat_room(info: &AccountInfo) -> Result<Option<ChatRoom>> {
+    let data = info.try_borrow_data()?;
+    if *info.owner != crate::ID || !data.starts_with(ChatRoom::DISCRIMINATOR) {
+        return Ok(None);
+    }
+    ChatRoom::try_deserialize(&mut &data[..]).map(Some)
+}
+
+/// Writes an account that was loaded or created by hand rather than through
+/// an `Account<T>`, which would otherwise serialize it on exit.
+fn store_account<T: AccountSerialize>(info: &AccountInfo, account: &T) -> Result<()> {
+    let mut data = info.try_borrow_mut_data()?;
+    let mut writer: &mut [u8] = &mut data[..];
+    account.try_serialize(&mut writer)
+}
+
+/// Creates a program-owned PDA at `target`, which must be the address
+/// derived from `seeds`. Used where the number of accounts is only known at
+/// runtime, so `init` constraints cannot be declared.
+fn create_pda_account<'info>(
+    target: &AccountInfo<'info>,
+    seeds: &[&[u8]],
+    space: usize,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let (address, bump) = Pubkey::find_program_address(seeds, &crate::ID);
+    require_keys_eq!(target.key(), address, ErrorCode::ConstraintSeeds);
+
+    let bump = [bump];
+    let mut signer_seeds = seeds.to_vec();
+    signer_seeds.push(&bump);
+    system_program::create_account(
+        CpiContext::new_with_signer(
+            system_program.clone(),
+            system_program::CreateAccount {
+                from: payer.clone(),
+                to: target.clone(),
+            },
+            &[&signer_seeds[..]],
+        ),
+        Rent::get()?.minimum_balance(space),
+        space as u64,
+        &crate::ID,
+    )
+}
+
+/// Post-increments a counter that seeds PDAs, refusing to wrap.
+fn next_id(counter: &mut u64) -> Result<u64> {
+    let id = *counter;
+    *counter = counter.checked_add(1).ok_or(ChatError::CounterOverflow)?;
+    Ok(id)
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64)]
+pub struct Initialize<'info> {
+    #[account(
+        init,
+        payer = user,
+        space = CHAT_ROOM_ACCOUNT_SIZE,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Authority-gated mutation of a chat room's settings.
+#[derive(Accounts)]
+pub struct UpdateChatRoom<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump,
+        has_one = authority @ ChatError::Unauthorized
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    pub new_authority: Signer<'info>,
+}
+
+/// A room action open to moderators and admins as well as the authority.
+#[derive(Accounts)]
+pub struct ModerateRoom<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), moderator.key().as_ref()],
+        bump
+    )]
+    pub moderator_membership: Option<Account<'info, Membership>>,
+    pub moderator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(member: Pubkey)]
+pub struct SetRole<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        mut,
+        seeds = [b"member", chat_room.key().as_ref(), member.as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, Membership>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), admin.key().as_ref()],
+        bump
+    )]
+    pub admin_membership: Option<Account<'info, Membership>>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(member: Pubkey)]
+pub struct AddMember<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump,
+        has_one = authority @ ChatError::Unauthorized
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init,
+        payer = authority,
+        space = MEMBERSHIP_ACCOUNT_SIZE,
+        seeds = [b"member", chat_room.key().as_ref(), member.as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, Membership>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveMember<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        mut,
+        seeds = [b"member", chat_room.key().as_ref(), membership.member.as_ref()],
+        bump,
+        close = moderator
+    )]
+    pub membership: Account<'info, Membership>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), moderator.key().as_ref()],
+        bump
+    )]
+    pub moderator_membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub moderator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(code_hash: [u8; 32])]
+pub struct CreateInvite<'info> {
+    #[account(
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump,
+        has_one = authority @ ChatError::Unauthorized
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init,
+        payer = authority,
+        space = INVITE_ACCOUNT_SIZE,
+        seeds = [b"invite", chat_room.key().as_ref(), code_hash.as_ref()],
+        bump
+    )]
+    pub invite: Account<'info, Invite>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RedeemInvite<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        mut,
+        seeds = [b"invite", chat_room.key().as_ref(), invite.code_hash.as_ref()],
+        bump
+    )]
+    pub invite: Account<'info, Invite>,
+    #[account(
+        init,
+        payer = redeemer,
+        space = MEMBERSHIP_ACCOUNT_SIZE,
+        seeds = [b"member", chat_room.key().as_ref(), redeemer.key().as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, Membership>,
+    #[account(mut)]
+    pub redeemer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PinMessage<'info> {
+    #[account(
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = moderator,
+        space = PINNED_MESSAGES_ACCOUNT_SIZE,
+        seeds = [b"pinned", chat_room.key().as_ref()],
+        bump
+    )]
+    pub pinned: Account<'info, PinnedMessages>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), moderator.key().as_ref()],
+        bump
+    )]
+    pub moderator_membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub moderator: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnpinMessage<'info> {
+    #[account(
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        mut,
+        seeds = [b"pinned", chat_room.key().as_ref()],
+        bump
+    )]
+    pub pinned: Account<'info, PinnedMessages>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), moderator.key().as_ref()],
+        bump
+    )]
+    pub moderator_membership: Option<Account<'info, Membership>>,
+    pub moderator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseChatRoom<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump,
+        has_one = authority @ ChatError::Unauthorized,
+        close = authority
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64, encrypted_message: Vec<u8>, recipient: Pubkey)]
+pub struct SendMessage<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", recipient.as_ref()],
+        bump
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: the recipient's `Block` PDA for this sender. Clients must always
+    /// pass this address; the send is rejected if a `Block` exists there.
+    #[account(
+        seeds = [b"block", recipient.as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub block: UncheckedAccount<'info>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", recipient.as_ref()], bump)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    /// Required in gated rooms; the sender's `Membership`.
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    /// Supplied to verify `content_signature` against the sender's
+    /// registered key; see `verify_content_signature`.
+    #[account(seeds = [b"key", sender.key().as_ref()], bump)]
+    pub key_registry: Option<Account<'info, KeyRegistry>>,
+    /// CHECK: the instructions sysvar, required alongside `key_registry`.
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: Option<UncheckedAccount<'info>>,
+    /// The message `reply_to` names, required with it; its `reply_count`
+    /// is incremented.
+    #[account(
+        mut,
+        constraint = parent.room == chat_room.key() @ ChatError::InvalidReplyTarget
+    )]
+    pub parent: Option<Account<'info, Message>>,
+    /// CHECK: the `SentMarker` at `[b"sent", sender, client_nonce]`, required
+    /// with a `client_nonce` and verified in `claim_client_nonce`.
+    #[account(mut)]
+    pub sent_marker: Option<UncheckedAccount<'info>>,
+    /// Signs to prove authorship; writable only because the tipping
+    /// variants move the tip out of the sender's account.
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    /// Funds the rent of every account the send creates. May be the sender
+    /// or a relayer sponsoring the sender.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64)]
+pub struct BatchSend<'info> {
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// Required in gated rooms; the sender's `Membership`.
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64)]
+pub struct SendSystemMessage<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump,
+        has_one = authority @ ChatError::Unauthorized
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    /// CHECK: the authority's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", authority.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64)]
+pub struct BroadcastToGroup<'info> {
+    #[account(
+        init,
+        payer = sender,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// Broadcasting requires membership even in open rooms; optional only
+    /// so that its absence reports `NotAMember`.
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(recipient: Pubkey)]
+pub struct SaveDraft<'info> {
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = DRAFT_ACCOUNT_SIZE,
+        seeds = [b"draft", owner.key().as_ref(), recipient.as_ref()],
+        bump
+    )]
+    pub draft: Account<'info, Draft>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteDraft<'info> {
+    #[account(
+        mut,
+        seeds = [b"draft", owner.key().as_ref(), draft.recipient.as_ref()],
+        bump,
+        close = owner
+    )]
+    pub draft: Account<'info, Draft>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64)]
+pub struct PromoteDraft<'info> {
+    #[account(
+        mut,
+        seeds = [b"draft", sender.key().as_ref(), draft.recipient.as_ref()],
+        bump,
+        close = sender
+    )]
+    pub draft: Account<'info, Draft>,
+    #[account(
+        init,
+        payer = sender,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", draft.recipient.as_ref()],
+        bump
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: the recipient's `Block` PDA for the sender, as in `SendMessage`.
+    #[account(
+        seeds = [b"block", draft.recipient.as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub block: UncheckedAccount<'info>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", draft.recipient.as_ref()], bump)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(new_recipient: Pubkey)]
+pub struct ForwardMessage<'info> {
+    #[account(constraint = original.recipient == forwarder.key() @ ChatError::CannotForward)]
+    pub original: Account<'info, Message>,
+    #[account(
+        init,
+        payer = forwarder,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = forwarder,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", new_recipient.as_ref()],
+        bump
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(
+        init_if_needed,
+        payer = forwarder,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), forwarder.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: `new_recipient`'s `Block` PDA for the forwarder, as in `SendMessage`.
+    #[account(
+        seeds = [b"block", new_recipient.as_ref(), forwarder.key().as_ref()],
+        bump
+    )]
+    pub block: UncheckedAccount<'info>,
+    /// CHECK: the forwarder's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", forwarder.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", new_recipient.as_ref()], bump)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), forwarder.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub forwarder: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64, encrypted_message: Vec<u8>, recipient: Pubkey)]
+pub struct SendMessageSized<'info> {
+    #[account(
+        init,
+        payer = sender,
+        space = sized_message_space(encrypted_message.len()),
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", recipient.as_ref()],
+        bump
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: the recipient's `Block` PDA for this sender, as in `SendMessage`.
+    #[account(
+        seeds = [b"block", recipient.as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub block: UncheckedAccount<'info>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", recipient.as_ref()], bump)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SendMessageWithTip<'info> {
+    pub send: SendMessage<'info>,
+    #[account(
+        init,
+        payer = send.payer,
+        space = TIP_ESCROW_ACCOUNT_SIZE,
+        seeds = [b"tip", send.message.key().as_ref()],
+        bump
+    )]
+    pub tip_escrow: Account<'info, TipEscrow>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimTip<'info> {
+    #[account(
+        mut,
+        seeds = [b"tip", tip_escrow.message.as_ref()],
+        bump,
+        has_one = recipient @ ChatError::NotRecipient,
+        close = recipient
+    )]
+    pub tip_escrow: Account<'info, TipEscrow>,
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SendMessageWithTokenTip<'info> {
+    pub send: SendMessage<'info>,
+    #[account(
+        init,
+        payer = send.payer,
+        space = TOKEN_TIP_ESCROW_ACCOUNT_SIZE,
+        seeds = [b"token_tip", send.message.key().as_ref()],
+        bump
+    )]
+    pub token_tip_escrow: Account<'info, TokenTipEscrow>,
+    #[account(
+        init,
+        payer = send.payer,
+        seeds = [b"token_tip_vault", send.message.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = token_tip_escrow
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        constraint = sender_token.mint == mint.key() @ ChatError::MintMismatch,
+        token::authority = send.sender
+    )]
+    pub sender_token: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimTokenTip<'info> {
+    #[account(
+        mut,
+        seeds = [b"token_tip", token_tip_escrow.message.as_ref()],
+        bump,
+        has_one = recipient @ ChatError::NotRecipient,
+        close = recipient
+    )]
+    pub token_tip_escrow: Account<'info, TokenTipEscrow>,
+    #[account(
+        mut,
+        seeds = [b"token_tip_vault", token_tip_escrow.message.as_ref()],
+        bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(constraint = mint.key() == token_tip_escrow.mint @ ChatError::MintMismatch)]
+    pub mint: Account<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = recipient,
+        associated_token::mint = mint,
+        associated_token::authority = recipient
+    )]
+    pub recipient_token: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(room_id: u64, recipient: Pubkey)]
+pub struct SendLargeMessage<'info> {
+    #[account(
+        init,
+        payer = sender,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            chat_room.key().as_ref(),
+            chat_room.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"chat_room", room_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", recipient.as_ref()],
+        bump
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = SENDER_STATE_ACCOUNT_SIZE,
+        seeds = [b"sender", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub sender_state: Account<'info, SenderState>,
+    /// CHECK: the recipient's `Block` PDA for this sender; see `SendMessage::block`.
+    #[account(
+        seeds = [b"block", recipient.as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub block: UncheckedAccount<'info>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", recipient.as_ref()], bump)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    /// Required in gated rooms; the sender's `Membership`.
+    #[account(
+        seeds = [b"member", chat_room.key().as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub membership: Option<Account<'info, Membership>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u16)]
+pub struct AppendChunk<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = sender @ ChatError::NotSender
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        init,
+        payer = sender,
+        space = MESSAGE_CHUNK_ACCOUNT_SIZE,
+        seeds = [b"chunk", message.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub chunk: Account<'info, MessageChunk>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(participant_a: Pubkey, participant_b: Pubkey)]
+pub struct InitConversation<'info> {
+    #[account(
+        init,
+        payer = initiator,
+        space = CONVERSATION_ACCOUNT_SIZE,
+        seeds = [b"conversation", participant_a.as_ref(), participant_b.as_ref()],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    #[account(mut)]
+    pub initiator: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SendDirectMessage<'info> {
+    #[account(
+        init,
+        payer = sender,
+        space = MESSAGE_ACCOUNT_SIZE,
+        seeds = [
+            b"message",
+            conversation.key().as_ref(),
+            conversation.message_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [
+            b"conversation",
+            conversation.participant_a.as_ref(),
+            conversation.participant_b.as_ref()
+        ],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    /// Records the current `session_epoch` on the message when passed.
+    #[account(seeds = [b"session", conversation.key().as_ref()], bump)]
+    pub session: Option<Account<'info, Session>>,
+    /// CHECK: the sender's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", sender.key().as_ref()], bump)]
+    pub sender_stats: UncheckedAccount<'info>,
+    /// CHECK: the other participant's `UserStats`, updated when it exists.
+    /// Its address is checked in `send_direct_message`, which works out who
+    /// the recipient is.
+    #[account(mut)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct EstablishSession<'info> {
+    #[account(
+        seeds = [
+            b"conversation",
+            conversation.participant_a.as_ref(),
+            conversation.participant_b.as_ref()
+        ],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    #[account(
+        init,
+        payer = participant_a,
+        space = SESSION_ACCOUNT_SIZE,
+        seeds = [b"session", conversation.key().as_ref()],
+        bump
+    )]
+    pub session: Account<'info, Session>,
+    #[account(mut, address = conversation.participant_a @ ChatError::NotAParticipant)]
+    pub participant_a: Signer<'info>,
+    #[account(address = conversation.participant_b @ ChatError::NotAParticipant)]
+    pub participant_b: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RotateSession<'info> {
+    #[account(
+        seeds = [
+            b"conversation",
+            conversation.participant_a.as_ref(),
+            conversation.participant_b.as_ref()
+        ],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    #[account(
+        mut,
+        seeds = [b"session", conversation.key().as_ref()],
+        bump
+    )]
+    pub session: Account<'info, Session>,
+    #[account(address = conversation.participant_a @ ChatError::NotAParticipant)]
+    pub participant_a: Signer<'info>,
+    #[account(address = conversation.participant_b @ ChatError::NotAParticipant)]
+    pub participant_b: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConversationState<'info> {
+    /// CHECK: a `ChatRoom` or `Conversation`; only its address is used.
+    #[account(owner = crate::ID)]
+    pub conversation: UncheckedAccount<'info>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = CONVERSATION_STATE_ACCOUNT_SIZE,
+        seeds = [b"conv_state", user.key().as_ref(), conversation.key().as_ref()],
+        bump
+    )]
+    pub conversation_state: Account<'info, ConversationState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetReadCursor<'info> {
+    /// CHECK: a `ChatRoom` or `Conversation`; only its address is used.
+    #[account(owner = crate::ID)]
+    pub conversation: UncheckedAccount<'info>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = READ_CURSOR_ACCOUNT_SIZE,
+        seeds = [b"cursor", user.key().as_ref(), conversation.key().as_ref()],
+        bump
+    )]
+    pub cursor: Account<'info, ReadCursor>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetPresence<'info> {
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = PRESENCE_ACCOUNT_SIZE,
+        seeds = [b"presence", user.key().as_ref()],
+        bump
+    )]
+    pub presence: Account<'info, Presence>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetReadReceipts<'info> {
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = USER_SETTINGS_ACCOUNT_SIZE,
+        seeds = [b"settings", user.key().as_ref()],
+        bump
+    )]
+    pub settings: Account<'info, UserSettings>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetTyping<'info> {
+    #[account(
+        seeds = [
+            b"conversation",
+            conversation.participant_a.as_ref(),
+            conversation.participant_b.as_ref()
+        ],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = TYPING_STATE_ACCOUNT_SIZE,
+        seeds = [b"typing", conversation.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub typing_state: Account<'info, TypingState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterKey<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = KEY_REGISTRY_ACCOUNT_SIZE,
+        seeds = [b"key", owner.key().as_ref()],
+        bump
+    )]
+    pub key_registry: Account<'info, KeyRegistry>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateKey<'info> {
+    #[account(
+        mut,
+        seeds = [b"key", owner.key().as_ref()],
+        bump,
+        has_one = owner
+    )]
+    pub key_registry: Account<'info, KeyRegistry>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: [u8; 32])]
+pub struct RegisterUsername<'info> {
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = USERNAME_ACCOUNT_SIZE,
+        seeds = [b"username", name.as_ref()],
+        bump
+    )]
+    pub username: Account<'info, Username>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = USER_PROFILE_ACCOUNT_SIZE,
+        seeds = [b"profile", owner.key().as_ref()],
+        bump
+    )]
+    pub profile: Account<'info, UserProfile>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReleaseUsername<'info> {
+    #[account(
+        mut,
+        seeds = [b"username", profile.name.as_ref()],
+        bump,
+        has_one = owner @ ChatError::Unauthorized,
+        close = owner
+    )]
+    pub username: Account<'info, Username>,
+    #[account(
+        mut,
+        seeds = [b"profile", owner.key().as_ref()],
+        bump,
+        close = owner
+    )]
+    pub profile: Account<'info, UserProfile>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddContact<'info> {
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = CONTACTS_ACCOUNT_SIZE,
+        seeds = [b"contacts", owner.key().as_ref()],
+        bump
+    )]
+    pub contacts: Account<'info, Contacts>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveContact<'info> {
+    #[account(
+        mut,
+        seeds = [b"contacts", owner.key().as_ref()],
+        bump
+    )]
+    pub contacts: Account<'info, Contacts>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(blocked: Pubkey)]
+pub struct BlockUser<'info> {
+    #[account(
+        init,
+        payer = blocker,
+        space = BLOCK_ACCOUNT_SIZE,
+        seeds = [b"block", blocker.key().as_ref(), blocked.as_ref()],
+        bump
+    )]
+    pub block: Account<'info, Block>,
+    #[account(mut)]
+    pub blocker: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnblockUser<'info> {
+    #[account(
+        mut,
+        seeds = [b"block", blocker.key().as_ref(), block.blocked.as_ref()],
+        bump,
+        has_one = blocker,
+        close = blocker
+    )]
+    pub block: Account<'info, Block>,
+    #[account(mut)]
+    pub blocker: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(muted: Pubkey)]
+pub struct MuteUser<'info> {
+    // `init_if_needed` so re-muting reports `AlreadyMuted`.
+    #[account(
+        init_if_needed,
+        payer = muter,
+        space = MUTE_ACCOUNT_SIZE,
+        seeds = [b"mute", muter.key().as_ref(), muted.as_ref()],
+        bump
+    )]
+    pub mute: Account<'info, Mute>,
+    #[account(mut)]
+    pub muter: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnmuteUser<'info> {
+    #[account(
+        mut,
+        seeds = [b"mute", muter.key().as_ref(), mute.muted.as_ref()],
+        bump,
+        has_one = muter,
+        close = muter
+    )]
+    pub mute: Account<'info, Mute>,
+    #[account(mut)]
+    pub muter: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitStats<'info> {
+    #[account(
+        init,
+        payer = user,
+        space = USER_STATS_ACCOUNT_SIZE,
+        seeds = [b"stats", user.key().as_ref()],
+        bump
+    )]
+    pub stats: Account<'info, UserStats>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitInbox<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", owner.key().as_ref()],
+        bump
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseInbox<'info> {
+    #[account(
+        mut,
+        seeds = [b"inbox", owner.key().as_ref()],
+        bump,
+        has_one = owner @ ChatError::Unauthorized,
+        close = owner
+    )]
+    pub inbox: Account<'info, UserInbox>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EditMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = sender @ ChatError::UnauthorizedEdit
+    )]
+    pub message: Account<'info, Message>,
+    /// CHECK: the message's `ChatRoom`, whose length limit applies, or the
+    /// `Conversation` of a direct message; see `load_chat_room`.
+    #[account(address = message.room)]
+    pub room: UncheckedAccount<'info>,
+    pub sender: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MarkDelivered<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = recipient @ ChatError::NotRecipient
+    )]
+    pub message: Account<'info, Message>,
+    pub recipient: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcknowledgeMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = recipient @ ChatError::NotRecipient
+    )]
+    pub message: Account<'info, Message>,
+    /// CHECK: the recipient's `UserInbox`, decremented when it exists.
+    #[account(mut, seeds = [b"inbox", recipient.key().as_ref()], bump)]
+    pub inbox: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", recipient.key().as_ref()], bump)]
+    pub recipient_stats: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `UserSettings`; read receipts are on without one.
+    #[account(seeds = [b"settings", recipient.key().as_ref()], bump)]
+    pub settings: UncheckedAccount<'info>,
+    /// CHECK: the recipient's `ReadMarker` for this message, created when
+    /// read receipts are off.
+    #[account(mut, seeds = [b"read", recipient.key().as_ref(), message.key().as_ref()], bump)]
+    pub read_marker: UncheckedAccount<'info>,
+    /// Pays for the `ReadMarker` when one is created.
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    /// CHECK: the message's sender, refunded when a `view_once` message closes.
+    #[account(mut, address = message.sender @ ChatError::Unauthorized)]
+    pub sender: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(new_recipient: Pubkey)]
+pub struct ReassignRecipient<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = sender @ ChatError::Unauthorized
+    )]
+    pub message: Account<'info, Message>,
+    /// Only room messages are indexed in inboxes and can be redirected;
+    /// a direct message's recipient is fixed by its conversation.
+    #[account(address = message.room)]
+    pub chat_room: Account<'info, ChatRoom>,
+    /// CHECK: the current recipient's `UserInbox`, updated when it exists.
+    #[account(mut, seeds = [b"inbox", message.recipient.as_ref()], bump)]
+    pub old_inbox: UncheckedAccount<'info>,
+    #[account(
+        init_if_needed,
+        payer = sender,
+        space = USER_INBOX_ACCOUNT_SIZE,
+        seeds = [b"inbox", new_recipient.as_ref()],
+        bump
+    )]
+    pub new_inbox: Account<'info, UserInbox>,
+    /// CHECK: the current recipient's `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", message.recipient.as_ref()], bump)]
+    pub old_recipient_stats: UncheckedAccount<'info>,
+    /// CHECK: `new_recipient`'s `UserStats`, updated when it exists.
+    #[account(mut, seeds = [b"stats", new_recipient.as_ref()], bump)]
+    pub new_recipient_stats: UncheckedAccount<'info>,
+    /// CHECK: `new_recipient`'s `Block` PDA for the sender, as in `SendMessage`.
+    #[account(
+        seeds = [b"block", new_recipient.as_ref(), sender.key().as_ref()],
+        bump
+    )]
+    pub block: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MarkAllRead<'info> {
+    /// CHECK: the owner's `UserInbox`; deserialized in the handler so a
+    /// missing inbox reports `NoInbox`.
+    #[account(mut, seeds = [b"inbox", owner.key().as_ref()], bump)]
+    pub inbox: UncheckedAccount<'info>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UnsendMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = sender @ ChatError::NotSender,
+        close = sender
+    )]
+    pub message: Account<'info, Message>,
+    /// CHECK: the message `reply_to` names, as in `DeleteMessage::parent`.
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.reply_to.unwrap_or_default().to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub parent: Option<UncheckedAccount<'info>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = sender @ ChatError::UnauthorizedDeletion,
+        close = sender
+    )]
+    pub message: Account<'info, Message>,
+    /// CHECK: the message `reply_to` names, required for replies; its
+    /// `reply_count` is decremented if it still exists.
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.reply_to.unwrap_or_default().to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub parent: Option<UncheckedAccount<'info>>,
+    #[account(mut)]
+    pub sender: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct HideMessage<'info> {
+    #[account(
+        constraint = message.sender == user.key() || message.recipient == user.key()
+            @ ChatError::NotAParticipant
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        init,
+        payer = user,
+        space = HIDDEN_MESSAGE_ACCOUNT_SIZE,
+        seeds = [b"hidden", user.key().as_ref(), message.key().as_ref()],
+        bump
+    )]
+    pub hidden: Account<'info, HiddenMessage>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnhideMessage<'info> {
+    #[account(
+        mut,
+        seeds = [b"hidden", user.key().as_ref(), hidden.message.as_ref()],
+        bump,
+        close = user
+    )]
+    pub hidden: Account<'info, HiddenMessage>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct StarMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        init,
+        payer = user,
+        space = STARRED_MESSAGE_ACCOUNT_SIZE,
+        seeds = [b"star", user.key().as_ref(), message.key().as_ref()],
+        bump
+    )]
+    pub starred: Account<'info, StarredMessage>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnstarMessage<'info> {
+    /// CHECK: the starred message, whose `star_count` is decremented if it
+    /// still exists.
+    #[account(mut, address = starred.message)]
+    pub message: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"star", user.key().as_ref(), starred.message.as_ref()],
+        bump,
+        close = user
+    )]
+    pub starred: Account<'info, StarredMessage>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReportMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+    #[account(
+        init,
+        payer = reporter,
+        space = REPORT_ACCOUNT_SIZE,
+        seeds = [b"report", message.key().as_ref(), reporter.key().as_ref()],
+        bump
+    )]
+    pub report: Account<'info, Report>,
+    #[account(mut)]
+    pub reporter: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddReaction<'info> {
+    #[account(mut)]
+    pub message: Account<'info, Message>,
+    #[account(
+        init_if_needed,
+        payer = reactor,
+        space = REACTIONS_ACCOUNT_SIZE,
+        seeds = [b"reactions", message.key().as_ref()],
+        bump
+    )]
+    pub reactions: Account<'info, Reactions>,
+    #[account(mut)]
+    pub reactor: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveReaction<'info> {
+    #[account(mut)]
+    pub message: Account<'info, Message>,
+    #[account(
+        mut,
+        seeds = [b"reactions", message.key().as_ref()],
+        bump
+    )]
+    pub reactions: Account<'info, Reactions>,
+    pub reactor: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMessage<'info> {
+    /// CHECK: a `Message` in any supported layout, which `Account<Message>`
+    /// could not load; validated by `read_versioned_message`.
+    #[account(mut, owner = crate::ID)]
+    pub message: UncheckedAccount<'info>,
+    /// The message's room, needed only when its authority migrates.
+    pub chat_room: Option<Account<'info, ChatRoom>>,
+    #[account(mut)]
+    pub migrator: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Messages and their senders arrive through `remaining_accounts`.
+#[derive(Accounts)]
+pub struct ReapExpiredBatch {}
+
+/// Messages and their senders arrive through `remaining_accounts`.
+#[derive(Accounts)]
+pub struct PurgeMessages<'info> {
+    #[account(
+        seeds = [b"chat_room", chat_room.room_id.to_le_bytes().as_ref()],
+        bump,
+        has_one = authority @ ChatError::Unauthorized
+    )]
+    pub chat_room: Account<'info, ChatRoom>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReapExpiredMessage<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.message_id.to_le_bytes().as_ref()
+        ],
+        bump,
+        has_one = sender,
+        close = sender
+    )]
+    pub message: Account<'info, Message>,
+    /// CHECK: the message `reply_to` names, as in `DeleteMessage::parent`.
+    #[account(
+        mut,
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message.reply_to.unwrap_or_default().to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub parent: Option<UncheckedAccount<'info>>,
+    /// CHECK: rent destination, constrained to `message.sender` via `has_one`.
+    #[account(mut)]
+    pub sender: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(message_id: u64)]
+pub struct GetMessage<'info> {
+    #[account(
+        seeds = [
+            b"message",
+            message.room.as_ref(),
+            message_id.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub message: Account<'info, Message>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct GetMessages<'info> {
+    /// CHECK: `user`'s `UserInbox`, which may not exist yet.
+    #[account(seeds = [b"inbox", user.as_ref()], bump)]
+    pub inbox: UncheckedAccount<'info>,
+}
+
+#[account]
+#[derive(InitSpace, Default)]
+pub struct ChatRoom {
+    /// Layout version; see `CHAT_ROOM_VERSION`.
+    pub version: u8,
+    pub message_count: u64,
+    pub room_id: u64,
+    /// Creator of the room, allowed to perform admin operations.
+    pub authority: Pubkey,
+    pub paused: bool,
+    pub rate_limit_window_secs: u32,
+    pub rate_limit_max_messages: u32,
+    pub member_count: u32,
+    /// When set, only senders with a `Membership` may post.
+    pub gated: bool,
+    /// Id and timestamp of the newest message, for chat-list previews.
+    /// Only meaningful once `message_count > 0`.
+    pub last_message_id: u64,
+    pub last_activity: i64,
+    /// Members holding `ROLE_ADMIN`, not counting the authority.
+    pub admin_count: u32,
+    /// Longest ciphertext this room accepts, at most `MAX_MESSAGE_LEN`.
+    pub max_message_len: u16,
+    /// Candidate authority awaiting `accept_authority`.
+    pub pending_authority: Option<Pubkey>,
+    /// When set, every message sent here expires this long after sending,
+    /// overriding any `expires_at` the sender asks for.
+    pub default_ttl_secs: Option<u32>,
+    /// Minimum seconds between any two messages in the room, whoever sends
+    /// them, measured from `last_room_message_at`; 0 disables slow mode.
+    /// Unlike the per-sender rate limit this throttles the room as a whole;
+    /// a `batch_send` counts as a single message.
+    pub slow_mode_secs: u32,
+    /// When a send that slow mode gates last went through; see
+    /// `authorize_room_send`. System messages do not touch it, so they
+    /// never hold members back.
+    pub last_room_message_at: i64,
+    /// Raised by `bump_security_epoch`; messages stamped with an older
+    /// epoch predate a key compromise and should not be trusted.
+    pub security_epoch: u64,
+    /// Lamports a sender must hold for `send_message` to accept their
+    /// message; 0 disables the gate. Only the balance is checked, nothing
+    /// is transferred or locked.
+    pub min_sender_balance: u64,
+    /// Seconds each sender must wait after their previous message in this
+    /// room, on top of the per-window cap; 0 disables the gap.
+    pub rate_limit_min_interval_secs: u32,
+}
+
+/// Per-room settings chosen at `initialize`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RoomConfig {
+    /// Quiet period after which a sender's message allowance resets.
+    pub rate_limit_window_secs: u32,
+    /// Messages a sender may post per window; 0 disables rate limiting.
+    pub rate_limit_max_messages: u32,
+    /// Restrict sending to members added via `add_member`.
+    pub gated: bool,
+    /// Per-room ciphertext cap. `Message` space is fixed at `MAX_MESSAGE_LEN`,
+    /// so this can only tighten the limit.
+    pub max_message_len: u16,
+    /// Disappearing-messages policy; see `ChatRoom::default_ttl_secs`.
+    pub default_ttl_secs: Option<u32>,
+    /// Sybil deterrent; see `ChatRoom::min_sender_balance`.
+    pub min_sender_balance: u64,
+    /// Per-sender spacing; see `ChatRoom::rate_limit_min_interval_secs`.
+    pub rate_limit_min_interval_secs: u32,
+}
+
+/// An off-chain attachment referenced from a message.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct Attachment {
+    /// One of `ATTACHMENT_IMAGE`, `ATTACHMENT_FILE` or `ATTACHMENT_AUDIO`.
+    pub kind: u8,
+    /// IPFS or Arweave identifier of the encrypted content.
+    pub cid: String,
+}
+
+impl Attachment {
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            (ATTACHMENT_IMAGE..=ATTACHMENT_AUDIO).contains(&self.kind),
+            ChatError::UnknownAttachmentKind
+        );
+        require!(!self.cid.is_empty(), ChatError::MissingAttachment);
+        require!(
+            self.cid.len() <= MAX_CID_LEN,
+            ChatError::AttachmentCidTooLong
+        );
+        Ok(())
+    }
+}
+
+impl ChatRoom {
+    /// Hands out the next message id. Message PDAs are seeded by this counter,
+    /// so it must never wrap.
+    pub fn next_message_id(&mut self) -> Result<u64> {
+        next_id(&mut self.message_count)
+    }
+
+    /// Expiry for a message sent at `now`: the room's TTL when it has one,
+    /// otherwise whatever the sender requested.
+    pub fn message_expiry(&self, now: i64, requested: Option<i64>) -> Option<i64> {
+        self.default_ttl_secs
+            .map(|ttl| now + i64::from(ttl))
+            .or(requested)
+    }
+
+    /// Enforces the room's `max_message_len` on top of `validate_ciphertext`.
+    pub fn check_message_len(&self, len: usize) -> Result<()> {
+        require!(
+            len <= usize::from(self.max_message_len),
+            ChatError::MessageTooLong
+        );
+        Ok(())
+    }
+
+    /// Whether slow mode still holds back the next message at `now`.
+    pub fn in_slow_mode(&self, now: i64) -> bool {
+        self.slow_mode_secs > 0
+            && now.saturating_sub(self.last_room_message_at) < i64::from(self.slow_mode_secs)
+    }
+
+    /// Assigns the next message id and records it as the room's latest
+    /// message, so the preview fields never lag the counter.
+    pub fn record_message(&mut self, now: i64) -> Result<u64> {
+        let message_id = self.next_message_id()?;
+        self.last_message_id = message_id;
+        self.last_activity = now;
+        Ok(message_id)
+    }
+}
+
+/// Hides `message` from `user`'s own view; see `hide_message`.
+#[account]
+#[derive(InitSpace)]
+pub struct HiddenMessage {
+    pub user: Pubkey,
+    pub message: Pubkey,
+    pub hidden_at: i64,
+}
+
+/// A user's bookmark on `message`; see `star_message`.
+#[account]
+#[derive(InitSpace)]
+pub struct StarredMessage {
+    pub user: Pubkey,
+    pub message: Pubkey,
+    pub starred_at: i64,
+}
+
+/// One user's report against a message, read off-chain by moderators.
+#[account]
+#[derive(InitSpace)]
+pub struct Report {
+    pub message: Pubkey,
+    pub reporter: Pubkey,
+    pub reason_code: u8,
+    pub reported_at: i64,
+}
+
+/// Aggregate reaction counters for one message, indexed by reaction kind.
+#[account]
+#[derive(InitSpace)]
+pub struct Reactions {
+    pub message: Pubkey,
+    pub counts: [u32; 6],
+}
+
+impl Reactions {
+    fn slot_mut(&mut self, reaction_index: u8) -> Result<&mut u32> {
+        self.counts
+            .get_mut(usize::from(reaction_index))
+            .ok_or_else(|| error!(ChatError::InvalidReaction))
+    }
+}
+
+/// Grants `member` the right to post in a gated room.
+#[account]
+#[derive(InitSpace)]
+pub struct Membership {
+    pub room: Pubkey,
+    pub member: Pubkey,
+    /// One of `ROLE_MEMBER`, `ROLE_MODERATOR` or `ROLE_ADMIN`.
+    pub role: u8,
+}
+
+/// Marks that `muter` silences notifications from `muted`; a client hint only.
+#[account]
+#[derive(InitSpace)]
+pub struct Mute {
+    pub muter: Pubkey,
+    pub muted: Pubkey,
+}
+
+/// A multi-use invite into a room, addressed by the hash of its code.
+#[account]
+#[derive(InitSpace)]
+pub struct Invite {
+    pub room: Pubkey,
+    pub code_hash: [u8; 32],
+    pub remaining_uses: u16,
+}
+
+/// A room's pinned announcements, in the order they were pinned.
+#[account]
+#[derive(InitSpace)]
+pub struct PinnedMessages {
+    pub room: Pubkey,
+    #[max_len(5)]
+    pub message_ids: Vec<u64>,
+}
+
+impl PinnedMessages {
+    pub fn pin(&mut self, message_id: u64) -> Result<()> {
+        require!(
+            !self.message_ids.contains(&message_id) && self.message_ids.len() < MAX_PINNED,
+            ChatError::PinLimitReached
+        );
+        self.message_ids.push(message_id);
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, message_id: u64) -> Result<()> {
+        let index = self
+            .message_ids
+            .iter()
+            .position(|id| *id == message_id)
+            .ok_or(ChatError::NotPinned)?;
+        self.message_ids.remove(index);
+        Ok(())
+    }
+}
+
+/// Marks that `blocker` refuses messages from `blocked`.
+#[account]
+#[derive(InitSpace)]
+pub struct Block {
+    pub blocker: Pubkey,
+    pub blocked: Pubkey,
+}
+
+/// Tracks one sender's recent activity in one room for rate limiting.
+#[account]
+#[derive(InitSpace, Default)]
+pub struct SenderState {
+    pub last_sent: i64,
+    pub messages_in_window: u32,
+}
+
+impl SenderState {
+    /// Counts a send at `now`. The allowance resets once the sender has been
+    /// quiet for a whole window since their previous message, and no send
+    /// may follow the previous one by less than `min_interval_secs`.
+    pub fn record_send(
+        &mut self,
+        now: i64,
+        window_secs: u32,
+        max_messages: u32,
+        min_interval_secs: u32,
+    ) -> Result<()> {
+        require!(
+            now.saturating_sub(self.last_sent) >= i64::from(min_interval_secs),
+            ChatError::RateLimited
+        );
+        if max_messages > 0 {
+            if now.saturating_sub(self.last_sent) >= i64::from(window_secs) {
+                self.messages_in_window = 0;
+            }
+            require!(
+                self.messages_in_window < max_messages,
+                ChatError::RateLimited
+            );
+            self.messages_in_window += 1;
+        }
+        self.last_sent = now;
+        Ok(())
+    }
+}
+
+/// One-to-one thread whose address either participant can derive.
+/// `participant_a` is always the lexicographically smaller key.
+#[account]
+#[derive(InitSpace)]
+pub struct Conversation {
+    pub participant_a: Pubkey,
+    pub participant_b: Pubkey,
+    pub message_count: u64,
+}
+
+impl Conversation {
+    pub fn next_message_id(&mut self) -> Result<u64> {
+        next_id(&mut self.message_count)
+    }
+
+    /// Returns the other participant, or `None` if `user` is not in this conversation.
+    pub fn peer_of(&self, user: &Pubkey) -> Option<Pubkey> {
+        if *user == self.participant_a {
+            Some(self.participant_b)
+        } else if *user == self.participant_b {
+            Some(self.participant_a)
+        } else {
+            None
+        }
+    }
+}
+
+/// Public anchor for a conversation's forward-secret key exchange:
+/// the ephemeral keys of the current epoch, indexed like the participants.
+#[account]
+#[derive(InitSpace)]
+pub struct Session {
+    pub conversation: Pubkey,
+    pub ephemeral_key_a: [u8; 32],
+    pub ephemeral_key_b: [u8; 32],
+    pub session_epoch: u64,
+    /// When the current epoch's keys were published.
+    pub established_at: i64,
+}
+
+impl Session {
+    pub fn start_epoch(&mut self, epoch: u64, key_a: [u8; 32], key_b: [u8; 32], now: i64) {
+        self.session_epoch = epoch;
+        self.ephemeral_key_a = key_a;
+        self.ephemeral_key_b = key_b;
+        self.established_at = now;
+    }
+}
+
+/// One user's view settings for a chat room or conversation. Clients filter
+/// their chat list by these; the program never reads them.
+#[account]
+#[derive(InitSpace)]
+pub struct ConversationState {
+    pub user: Pubkey,
+    pub conversation: Pubkey,
+    pub archived: bool,
+    pub muted: bool,
+}
+
+/// One user's scroll position in a chat room or conversation.
+#[account]
+#[derive(InitSpace, Default)]
+pub struct ReadCursor {
+    pub last_read_message_id: u64,
+    pub updated_at: i64,
+}
+
+/// Records that a sender used a `client_nonce`, so a resubmitted
+/// `send_message` fails with `DuplicateSend` instead of sending twice.
+#[account]
+#[derive(InitSpace)]
+pub struct SentMarker {}
+
+/// Records that a recipient with read receipts off acknowledged a message,
+/// in place of the `read_at` they chose not to set.
+#[account]
+#[derive(InitSpace)]
+pub struct ReadMarker {}
+
+/// Per-user privacy preferences. Users without one get the defaults.
+#[account]
+#[derive(InitSpace)]
+pub struct UserSettings {
+    pub read_receipts_enabled: bool,
+}
+
+/// A user's last reported online status.
+#[account]
+#[derive(InitSpace, Default)]
+pub struct Presence {
+    /// One of the `PRESENCE_*` values.
+    pub status: u8,
+    pub last_seen: i64,
+}
+
+impl Presence {
+    /// The status clients should display: stale heartbeats read as offline.
+    pub fn effective_status(&self, now: i64) -> u8 {
+        if now.saturating_sub(self.last_seen) > PRESENCE_STALE_SECS {
+            PRESENCE_OFFLINE
+        } else {
+            self.status
+        }
     }
+}
 
-    pub fn send_message(
-        ctx: Context<SendMessage>,
-        encrypted_message: Vec<u8>,
-        recipient: Pubkey,
-    ) -> Result<()> {
-        let message = &mut ctx.accounts.message;
-        let chat_room = &mut ctx.accounts.chat_room;
-        
-        message.sender = ctx.accounts.sender.key();
-        message.recipient = recipient;
-        message.encrypted_content = encrypted_message;
-        message.timestamp = Clock::get()?.unix_timestamp;
-        message.message_id = chat_room.message_count;
-        
-        chat_room.message_count += 1;
-        
-        msg!("Message sent from {} to {}", message.sender, message.recipient);
+/// A participant's typing indicator; see `TYPING_STALE_SECS`.
+#[account]
+#[derive(InitSpace)]
+pub struct TypingState {
+    pub is_typing: bool,
+    pub updated_at: i64,
+}
+
+/// `version`, `sender` and `recipient` must stay the first three fields:
+/// `migrate_message` relies on the version leading, and clients filter on
+/// `SENDER_OFFSET` / `RECIPIENT_OFFSET`. Append new fields at the end and
+/// bump `MESSAGE_VERSION`.
+#[account]
+#[derive(InitSpace)]
+pub struct Message {
+    /// Layout version; see `MESSAGE_VERSION`.
+    pub version: u8,
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
+    #[max_len(512)]
+    pub encrypted_content: Vec<u8>,
+    pub timestamp: i64,
+    pub message_id: u64,
+    pub edited_at: Option<i64>,
+    pub read_at: Option<i64>,
+    pub algorithm: u8,
+    pub nonce: [u8; 24],
+    pub reply_to: Option<u64>,
+    /// Chat room or conversation the message was sent in; part of the message PDA seeds.
+    pub room: Pubkey,
+    /// After this time anyone may close the account via `reap_expired_message`.
+    pub expires_at: Option<i64>,
+    /// Number of `MessageChunk`s holding the ciphertext; 0 for inline messages.
+    pub chunk_count: u16,
+    pub chunks_received: u16,
+    pub delivered_at: Option<i64>,
+    /// Detached ed25519 signature over `content_hash`; all zero if unsigned.
+    pub content_signature: [u8; 64],
+    pub report_count: u32,
+    /// One of the `ATTACHMENT_*` kinds; `ATTACHMENT_NONE` leaves the CID empty.
+    pub attachment_kind: u8,
+    #[max_len(64)]
+    pub attachment_cid: String,
+    /// One of the `PRIORITY_*` levels.
+    pub priority: u8,
+    pub edit_count: u16,
+    /// Hashes of the ciphertext replaced by the most recent edits, oldest first.
+    #[max_len(5)]
+    pub prev_content_hashes: Vec<[u8; 32]>,
+    /// Position among all messages indexed in the recipient's inbox, taken
+    /// from `UserInbox::message_count`. Contiguous per recipient, so a gap
+    /// means a message was missed; restarts at 0 if the inbox is closed.
+    /// Direct messages are not indexed and leave this at 0.
+    pub recipient_seq: u64,
+    /// Room id of a `broadcast_to_group` message, whose recipient is the room.
+    pub group_id: Option<u64>,
+    /// Sum of the counts in this message's `Reactions` PDA, mirrored here so
+    /// clients can show a total without fetching the breakdown.
+    pub total_reactions: u32,
+    /// Id of the message this one was forwarded from, in the original's room.
+    pub forwarded_from: Option<u64>,
+    /// keccak-256 of `encrypted_content`, for integrity checks and dedup.
+    /// All zero for large messages, whose content lives in chunks.
+    pub content_hash: [u8; 32],
+    /// Epoch of the conversation's `Session` a direct message was encrypted
+    /// under, when the sender passed one.
+    pub session_epoch: Option<u64>,
+    /// Set by `send_system_message` for automated notices, which clients
+    /// render apart from conversation bubbles.
+    pub is_system: bool,
+    /// Closed by `acknowledge_message` as soon as the recipient reads it.
+    pub view_once: bool,
+    /// Live replies sent to this room message through `send_message`;
+    /// `delete_message`, `unsend_message` and `reap_expired_message` on a
+    /// reply take it back off. The bulk `reap_expired_batch` and
+    /// `purge_messages` do not, so after those the count may run high.
+    pub reply_count: u32,
+    /// Number of users with a `StarredMessage` for this message.
+    pub star_count: u32,
+    /// The parent's `content_hash` when this reply was sent, if the sender
+    /// quoted it; see `send_message`.
+    pub quoted_content_hash: Option<[u8; 32]>,
+    /// The room's `security_epoch` when this message was sent; always 0
+    /// for direct messages.
+    pub security_epoch: u64,
+    /// The plaintext was compressed before encryption, so recipients inflate
+    /// it after decrypting. The program never looks inside the content.
+    pub compressed: bool,
+    /// Per-member message keys of a `send_group_message` message; empty
+    /// for every other message. `send_message_sized` leaves out their space.
+    #[max_len(6)]
+    pub sealed_keys: Vec<[u8; SEALED_KEY_LEN]>,
+    /// `content_signature` was checked against the sender's `KeyRegistry`
+    /// key when sent. Senders choose whether to pass their registry, so an
+    /// unverified signature says nothing about who wrote the message.
+    pub signature_verified: bool,
+}
+
+// Written out because `Default` is not derived for `[u8; 64]`.
+impl Default for Message {
+    fn default() -> Self {
+        Self {
+            version: MESSAGE_VERSION,
+            sender: Pubkey::default(),
+            recipient: Pubkey::default(),
+            encrypted_content: Vec::new(),
+            timestamp: 0,
+            message_id: 0,
+            edited_at: None,
+            read_at: None,
+            algorithm: 0,
+            nonce: [0; 24],
+            reply_to: None,
+            room: Pubkey::default(),
+            expires_at: None,
+            chunk_count: 0,
+            chunks_received: 0,
+            delivered_at: None,
+            content_signature: [0; 64],
+            report_count: 0,
+            attachment_kind: ATTACHMENT_NONE,
+            attachment_cid: String::new(),
+            priority: PRIORITY_NORMAL,
+            edit_count: 0,
+            prev_content_hashes: Vec::new(),
+            recipient_seq: 0,
+            group_id: None,
+            total_reactions: 0,
+            forwarded_from: None,
+            content_hash: [0; 32],
+            session_epoch: None,
+            is_system: false,
+            view_once: false,
+            reply_count: 0,
+            star_count: 0,
+            quoted_content_hash: None,
+            security_epoch: 0,
+            compressed: false,
+            sealed_keys: Vec::new(),
+            signature_verified: false,
+        }
+    }
+}
+
+impl Message {
+    pub fn is_complete(&self) -> bool {
+        self.chunks_received == self.chunk_count
+    }
+
+    pub fn within_recall_window(&self, now: i64) -> bool {
+        now.saturating_sub(self.timestamp) <= RECALL_WINDOW_SECS
+    }
+
+    pub fn within_edit_window(&self, now: i64) -> bool {
+        now.saturating_sub(self.timestamp) <= EDIT_WINDOW_SECS
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(deadline) if now >= deadline)
+    }
+
+    /// Stores the ciphertext together with its `content_hash`.
+    pub fn set_content(&mut self, content: Vec<u8>) {
+        self.content_hash = keccak::hash(&content).to_bytes();
+        self.encrypted_content = content;
+    }
+
+    /// Replaces the ciphertext, keeping a keccak hash of the old one so the
+    /// edit history is auditable without storing prior content.
+    pub fn record_edit(&mut self, new_content: Vec<u8>, now: i64) {
+        if self.prev_content_hashes.len() == MAX_EDIT_HISTORY {
+            self.prev_content_hashes.remove(0);
+        }
+        self.prev_content_hashes.push(keccak::hash(&self.encrypted_content).to_bytes());
+        self.edit_count = self.edit_count.saturating_add(1);
+        self.set_content(new_content);
+        self.edited_at = Some(now);
+    }
+}
+
+/// One ordered slice of a large message's ciphertext.
+#[account]
+#[derive(InitSpace)]
+pub struct MessageChunk {
+    pub message: Pubkey,
+    pub index: u16,
+    #[max_len(900)]
+    pub data: Vec<u8>,
+}
+
+/// Dashboard totals for one user, kept up to date by every send instruction
+/// and `acknowledge_message` once `init_stats` has created the account.
+/// Room-wide sends (broadcasts, group and system messages) count for their
+/// sender only, since they have no single recipient.
+#[account]
+#[derive(InitSpace)]
+pub struct UserStats {
+    pub user: Pubkey,
+    pub total_sent: u64,
+    pub total_received: u64,
+    pub total_unread: u64,
+}
+
+impl UserStats {
+    pub fn record_sent(&mut self) -> Result<()> {
+        self.total_sent = self
+            .total_sent
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
         Ok(())
     }
 
-    pub fn get_messages_for_user(
-        _ctx: Context<GetMessages>,
-        _user: Pubkey,
-    ) -> Result<()> {
-        msg!("Getting messages for user");
+    pub fn record_received(&mut self) -> Result<()> {
+        self.total_received = self
+            .total_received
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
+        self.total_unread = self
+            .total_unread
+            .checked_add(1)
+            .ok_or(ChatError::CounterOverflow)?;
         Ok(())
     }
+
+    /// Saturates rather than failing: a message received before `init_stats`
+    /// was never counted as unread, and acknowledging it must still work.
+    pub fn record_read(&mut self) {
+        self.total_unread = self.total_unread.saturating_sub(1);
+    }
+
+    /// Takes back a `record_received` for a message redirected elsewhere
+    /// before it was delivered, and so before it could be read.
+    pub fn record_unreceived(&mut self) {
+        self.total_received = self.total_received.saturating_sub(1);
+        self.record_read();
+    }
 }
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(
-        init,
-        payer = user,
-        space = 8 + ChatRoom::INIT_SPACE,
-        seeds = [b"chat_room"],
-        bump
-    )]
-    pub chat_room: Account<'info, ChatRoom>,
-    #[account(mut)]
-    pub user: Signer<'info>,
-    pub system_program: Program<'info, System>,
+#[account]
+#[derive(InitSpace, Default)]
+pub struct UserInbox {
+    pub owner: Pubkey,
+    pub message_count: u64,
+    /// Most recent messages addressed to `owner`, oldest first.
+    #[max_len(16)]
+    pub recent_messages: Vec<InboxEntry>,
+    /// Badge count: messages received but not yet acknowledged.
+    pub unread_count: u64,
+    pub last_read_at: Option<i64>,
 }
 
-#[derive(Accounts)]
-pub struct SendMessage<'info> {
-    #[account(
-        init,
-        payer = sender,
-        space = 8 + Message::INIT_SPACE,
-        seeds = [b"message", chat_room.message_count.to_le_bytes().as_ref()],
-        bump
-    )]
-    pub message: Account<'info, Message>,
-    #[account(
-        mut,
-        seeds = [b"chat_room"],
-        bump
-    )]
-    pub chat_room: Account<'info, ChatRoom>,
-    #[account(mut)]
-    pub sender: Signer<'info>,
-    pub system_program: Program<'info, System>,
+/// Return data of `reap_expired_message` and `reap_expired_batch`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ReapResult {
+    pub closed_count: u16,
+    /// Rent refunded to the senders of the closed messages.
+    pub lamports_reclaimed: u64,
 }
 
-#[derive(Accounts)]
-pub struct GetMessages<'info> {
-    pub user: Signer<'info>,
+/// Return data of `get_message`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MessageView {
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
+    pub timestamp: i64,
+    pub message_id: u64,
+    pub content_len: u32,
+}
+
+/// Locates a message: ids are only unique within their room.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct InboxEntry {
+    pub room: Pubkey,
+    pub message_id: u64,
+}
+
+impl UserInbox {
+    /// Records a new message, evicting the oldest id once the ring is full.
+    /// Returns the message's sequence number among those sent to `owner`.
+    pub fn push(&mut self, room: Pubkey, message_id: u64) -> Result<u64> {
+        if self.recent_messages.len() == INBOX_CAPACITY {
+            self.recent_messages.remove(0);
+        }
+        self.recent_messages.push(InboxEntry { room, message_id });
+        self.unread_count = self.unread_count.saturating_add(1);
+        next_id(&mut self.message_count)
+    }
+
+    /// Undoes `push` for an unread message that is no longer addressed to
+    /// `owner`. The entry may already have been evicted from the ring; the
+    /// unread count still included it.
+    pub fn remove(&mut self, room: Pubkey, message_id: u64) {
+        self.recent_messages
+            .retain(|entry| entry.room != room || entry.message_id != message_id);
+        self.unread_count = self.unread_count.saturating_sub(1);
+    }
 }
 
+/// Holds a tip attached to `message` until its recipient claims it. The
+/// tip is the lamports above rent; closing the escrow pays out both.
 #[account]
 #[derive(InitSpace)]
-pub struct ChatRoom {
-    pub message_count: u64,
+pub struct TipEscrow {
+    pub message: Pubkey,
+    pub recipient: Pubkey,
+    pub amount_lamports: u64,
 }
 
+/// Token counterpart of `TipEscrow`; owns the vault holding the tip.
 #[account]
 #[derive(InitSpace)]
-pub struct Message {
-    pub sender: Pubkey,
+pub struct TokenTipEscrow {
+    pub message: Pubkey,
+    pub recipient: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+/// An unsent message to `recipient`, synced across the owner's devices.
+#[account]
+#[derive(InitSpace)]
+pub struct Draft {
+    pub owner: Pubkey,
     pub recipient: Pubkey,
     #[max_len(512)]
     pub encrypted_content: Vec<u8>,
+    pub algorithm: u8,
+    pub nonce: [u8; 24],
+    pub updated_at: i64,
+}
+
+/// A user's saved recipients, in the order they were added.
+#[account]
+#[derive(InitSpace)]
+pub struct Contacts {
+    pub owner: Pubkey,
+    #[max_len(32)]
+    pub contacts: Vec<Pubkey>,
+}
+
+impl Contacts {
+    pub fn add(&mut self, contact: Pubkey) -> Result<()> {
+        require!(!self.contacts.contains(&contact), ChatError::ContactExists);
+        require!(
+            self.contacts.len() < MAX_CONTACTS,
+            ChatError::ContactLimitReached
+        );
+        self.contacts.push(contact);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, contact: &Pubkey) -> Result<()> {
+        let index = self
+            .contacts
+            .iter()
+            .position(|c| c == contact)
+            .ok_or(ChatError::ContactNotFound)?;
+        self.contacts.remove(index);
+        Ok(())
+    }
+}
+
+/// Maps a unique username to its owner.
+#[account]
+#[derive(InitSpace)]
+pub struct Username {
+    pub owner: Pubkey,
+    pub name: [u8; 32],
+}
+
+/// Reverse lookup from an owner to their username.
+#[account]
+#[derive(InitSpace)]
+pub struct UserProfile {
+    pub owner: Pubkey,
+    pub name: [u8; 32],
+}
+
+/// Publishes a user's encryption key, which is distinct from their wallet signing key.
+#[account]
+#[derive(InitSpace)]
+pub struct KeyRegistry {
+    pub owner: Pubkey,
+    pub encryption_pubkey: [u8; 32],
+    pub updated_at: i64,
+}
+
+#[event]
+pub struct ChatRoomInitialized {
+    pub chat_room: Pubkey,
+    pub room_id: u64,
+}
+
+#[event]
+pub struct MessageSent {
+    pub message_id: u64,
+    pub room: Pubkey,
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
     pub timestamp: i64,
+    pub reply_to: Option<u64>,
+    pub attachment_kind: u8,
+    pub priority: u8,
+    pub content_hash: [u8; 32],
+}
+
+#[event]
+pub struct MessageDelivered {
+    pub message_id: u64,
+    pub recipient: Pubkey,
+}
+
+#[event]
+pub struct MessageRead {
+    pub message_id: u64,
+    pub reader: Pubkey,
+}
+
+#[event]
+pub struct KeyRegistered {
+    pub owner: Pubkey,
+    pub encryption_pubkey: [u8; 32],
+    pub rotated: bool,
+}
+
+#[event]
+pub struct RoomPaused {
+    pub chat_room: Pubkey,
+}
+
+#[event]
+pub struct RoomUnpaused {
+    pub chat_room: Pubkey,
+}
+
+#[event]
+pub struct MessageRecalled {
+    pub message_id: u64,
+    pub room: Pubkey,
+}
+
+#[event]
+pub struct ReactionChanged {
+    pub message: Pubkey,
+    pub reaction_index: u8,
+    pub count: u32,
+}
+
+#[event]
+pub struct InboxClosed {
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct MessagePinned {
+    pub chat_room: Pubkey,
+    pub message_id: u64,
+}
+
+#[event]
+pub struct MessageUnpinned {
+    pub chat_room: Pubkey,
     pub message_id: u64,
 }
+
+#[event]
+pub struct SessionEstablished {
+    pub conversation: Pubkey,
+    pub session_epoch: u64,
+}
+
+#[event]
+pub struct MessagesReaped {
+    pub closed_count: u16,
+    pub lamports_reclaimed: u64,
+}
+
+#[event]
+pub struct SecurityEpochBumped {
+    pub chat_room: Pubkey,
+    pub security_epoch: u64,
+}
+
+#[event]
+pub struct PresenceChanged {
+    pub user: Pubkey,
+    pub status: u8,
+}
+
+#[event]
+pub struct TypingChanged {
+    pub conversation: Pubkey,
+    pub user: Pubkey,
+    pub is_typing: bool,
+}
+
+/// Every failure the program reports. Anchor numbers the variants from 6000
+/// in declaration order and clients branch on those codes, so variants are
+/// only ever appended, and retired ones stay in place with a note.
+#[error_code]
+pub enum ChatError {
+    #[msg("Only the original sender can delete this message")]
+    UnauthorizedDeletion,
+    #[msg("Only the original sender can edit this message")]
+    UnauthorizedEdit,
+    #[msg("Encrypted message exceeds the 512-byte limit")]
+    MessageTooLong,
+    #[msg("Encrypted message must not be empty")]
+    EmptyMessage,
+    #[msg("Message counter overflowed")]
+    CounterOverflow,
+    #[msg("Only the recipient can acknowledge this message")]
+    NotRecipient,
+    #[msg("Message has already been read")]
+    AlreadyRead,
+    #[msg("Unknown cipher suite identifier")]
+    UnknownAlgorithm,
+    #[msg("Nonce must not be all zeroes")]
+    InvalidNonce,
+    #[msg("Replies must reference an existing message")]
+    InvalidReplyTarget,
+    #[msg("Signer is not a participant in this conversation")]
+    NotAParticipant,
+    #[msg("Conversation participants must be passed in ascending order")]
+    UnsortedParticipants,
+    #[msg("Signer is not the chat room authority")]
+    Unauthorized,
+    #[msg("Chat room is paused")]
+    RoomPaused,
+    #[msg("Sender is rate limited in this room")]
+    RateLimited,
+    #[msg("Recipient has blocked this sender")]
+    SenderBlocked,
+    #[msg("Message has not expired yet")]
+    NotExpired,
+    #[msg("Reaction index is out of range")]
+    InvalidReaction,
+    #[msg("Only the original sender can modify this message")]
+    NotSender,
+    #[msg("Large messages need between 1 and 64 chunks")]
+    InvalidChunkCount,
+    #[msg("Chunk exceeds the 900-byte limit")]
+    ChunkTooLarge,
+    #[msg("Chunks must be appended in order")]
+    UnexpectedChunkIndex,
+    #[msg("Message has already been delivered")]
+    AlreadyDelivered,
+    #[msg("Message must be delivered before it can be read")]
+    NotDelivered,
+    #[msg("Recall window has expired")]
+    RecallWindowExpired,
+    #[msg("Sender is not a member of this room")]
+    NotAMember,
+    #[msg("Inbox has not been initialized")]
+    NoInbox,
+    #[msg("Deadline is in the past")]
+    DeadlineInPast,
+    #[msg("Deadline is more than a year away")]
+    DeadlineTooFar,
+    #[msg("Tip amount must be greater than zero")]
+    ZeroTip,
+    #[msg("Token account mint does not match the tip's mint")]
+    MintMismatch,
+    /// No longer returned; superseded by `SignatureVerificationFailed`.
+    #[msg("Content signature does not verify against the sender's registered key")]
+    InvalidSignature,
+    #[msg("No message with this id exists in the room")]
+    MessageNotFound,
+    #[msg("Message is already pinned or the room has no pin slots left")]
+    PinLimitReached,
+    #[msg("Message is not pinned")]
+    NotPinned,
+    #[msg("A batch must have between 1 and 8 recipients")]
+    BatchTooLarge,
+    #[msg("Batch recipients, ciphertexts and nonces must have the same length")]
+    BatchLengthMismatch,
+    #[msg("Senders cannot report their own messages")]
+    CannotReportOwn,
+    #[msg("Unknown report reason code")]
+    InvalidReasonCode,
+    #[msg("Username is already registered")]
+    UsernameTaken,
+    #[msg("Usernames are 1-32 characters of a-z, 0-9 and _")]
+    InvalidUsername,
+    #[msg("Release the current username before registering another")]
+    AlreadyHasUsername,
+    #[msg("Contact is already saved")]
+    ContactExists,
+    #[msg("Contact list is full")]
+    ContactLimitReached,
+    #[msg("Contact is not in the list")]
+    ContactNotFound,
+    #[msg("Attachment kind requires a non-empty CID")]
+    MissingAttachment,
+    #[msg("Unknown attachment kind")]
+    UnknownAttachmentKind,
+    #[msg("Attachment CID exceeds the 64-byte limit")]
+    AttachmentCidTooLong,
+    #[msg("Unknown message priority")]
+    InvalidPriority,
+    #[msg("User is already muted")]
+    AlreadyMuted,
+    #[msg("Unknown presence status")]
+    InvalidPresenceStatus,
+    #[msg("Invite has no uses left")]
+    InviteExhausted,
+    #[msg("Invite code does not match")]
+    InvalidInviteCode,
+    #[msg("Unknown member role")]
+    InvalidRole,
+    #[msg("The last admin cannot step down")]
+    LastAdmin,
+    #[msg("Only the recipient of a message can forward it")]
+    CannotForward,
+    #[msg("Room config exceeds the compiled limits")]
+    ConfigTooLarge,
+    #[msg("Account layout is not a known version")]
+    UnknownAccountVersion,
+    #[msg("Signer is not the pending authority")]
+    NotPendingAuthority,
+    #[msg("A message was already sent with this client nonce")]
+    DuplicateSend,
+    #[msg("A client nonce requires its sent marker account")]
+    MissingSentMarker,
+    #[msg("Message can no longer be edited")]
+    EditWindowExpired,
+    #[msg("Purge range extends past the room's messages")]
+    InvalidPurgeRange,
+    #[msg("No preceding ed25519 instruction signs the content hash with the sender's key")]
+    SignatureVerificationFailed,
+    #[msg("Room is in slow mode; wait before sending")]
+    SlowMode,
+    #[msg("Account has the wrong owner or type")]
+    InvalidAccount,
+    #[msg("Read cursor cannot move backward")]
+    CursorRegression,
+    #[msg("Quoted hash does not match the parent message")]
+    QuoteMismatch,
+    #[msg("Sender balance is below this room's minimum")]
+    InsufficientBalance,
+    #[msg("Compressed payload is too short to be valid")]
+    InvalidCompressedPayload,
+    #[msg("Group messages need one sealed key per member, for at most 6 members")]
+    SealedKeyCountMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_counter_overflow_is_an_error() {
+        let mut chat_room = ChatRoom {
+            message_count: u64::MAX - 1,
+            ..Default::default()
+        };
+
+        assert_eq!(chat_room.next_message_id().unwrap(), u64::MAX - 1);
+        assert_eq!(
+            chat_room.next_message_id().unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::CounterOverflow)
+        );
+        assert_eq!(chat_room.message_count, u64::MAX);
+    }
+
+    #[test]
+    fn message_layout_keeps_sender_and_recipient_offsets() {
+        let message = Message {
+            sender: Pubkey::new_unique(),
+            recipient: Pubkey::new_unique(),
+            encrypted_content: vec![7; 32],
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        message.try_serialize(&mut data).unwrap();
+
+        let sender = SENDER_OFFSET as usize;
+        let recipient = RECIPIENT_OFFSET as usize;
+        assert_eq!(&data[sender..sender + 32], message.sender.as_ref());
+        assert_eq!(&data[recipient..recipient + 32], message.recipient.as_ref());
+    }
+
+    #[test]
+    fn legacy_messages_upgrade_to_the_current_layout() {
+        let message = Message {
+            version: 2,
+            sender: Pubkey::new_unique(),
+            recipient: Pubkey::new_unique(),
+            encrypted_content: vec![7; 32],
+            message_id: 42,
+            ..Default::default()
+        };
+        let mut v2 = Vec::new();
+        message.try_serialize(&mut v2).unwrap();
+        v2.resize(MESSAGE_V2_ACCOUNT_SIZE, 0);
+        // Version 1 held the same fields without the version byte
+        let mut v1 = v2.clone();
+        v1.remove(8);
+
+        for legacy in [v1, v2] {
+            let upgraded = read_versioned_message(&legacy).unwrap();
+            assert_eq!(upgraded.version, MESSAGE_VERSION);
+            assert_eq!(upgraded.sender, message.sender);
+            assert_eq!(upgraded.recipient, message.recipient);
+            assert_eq!(upgraded.encrypted_content, message.encrypted_content);
+            assert_eq!(upgraded.message_id, 42);
+            assert_eq!(upgraded.content_hash, keccak::hash(&[7; 32]).to_bytes());
+        }
+
+        let mut current = Message::default();
+        current.set_content(vec![1, 2, 3]);
+        let mut data = Vec::new();
+        current.try_serialize(&mut data).unwrap();
+        data.resize(MESSAGE_ACCOUNT_SIZE, 0);
+        assert_eq!(
+            read_versioned_message(&data).unwrap().content_hash,
+            current.content_hash
+        );
+        assert_eq!(
+            read_versioned_message(&data[..100]).err().unwrap(),
+            anchor_lang::error::Error::from(ChatError::UnknownAccountVersion)
+        );
+    }
+
+    #[test]
+    fn message_account_size_fits_a_full_message() {
+        let message = Message {
+            encrypted_content: vec![0; MAX_MESSAGE_LEN],
+            edited_at: Some(0),
+            read_at: Some(0),
+            reply_to: Some(0),
+            expires_at: Some(0),
+            delivered_at: Some(0),
+            attachment_cid: "c".repeat(MAX_CID_LEN),
+            prev_content_hashes: vec![[0; 32]; MAX_EDIT_HISTORY],
+            group_id: Some(0),
+            forwarded_from: Some(0),
+            session_epoch: Some(0),
+            quoted_content_hash: Some([0; 32]),
+            sealed_keys: vec![[0; SEALED_KEY_LEN]; MAX_SEALED_KEYS],
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        message.try_serialize(&mut data).unwrap();
+
+        assert_eq!(data.len(), MESSAGE_ACCOUNT_SIZE);
+        assert_eq!(costs::AccountKind::Message.size(), MESSAGE_ACCOUNT_SIZE);
+    }
+
+    #[test]
+    fn sized_messages_never_take_a_legacy_size() {
+        assert!(sized_message_space(20) < sized_message_space(400));
+        for len in 1..=MAX_MESSAGE_LEN {
+            let space = sized_message_space(len);
+            assert!(space <= MESSAGE_ACCOUNT_SIZE);
+            assert!(space < MESSAGE_V1_ACCOUNT_SIZE || space == MESSAGE_ACCOUNT_SIZE);
+        }
+    }
+
+    #[test]
+    fn sized_messages_cost_less_rent_than_full_ones() {
+        let rent = Rent::default();
+        let full = costs::rent_exempt_lamports(&rent, costs::AccountKind::Message);
+        assert!(costs::sized_message_rent(&rent, 20) < full);
+        assert_eq!(costs::sized_message_rent(&rent, MAX_MESSAGE_LEN), full);
+        // Group keys would otherwise eat most of the saving
+        assert!(costs::sized_message_rent(&rent, MAX_MESSAGE_LEN / 2) < full);
+    }
+
+    #[test]
+    fn sized_messages_from_every_layout_stay_readable() {
+        let mut message = Message {
+            version: 6,
+            message_id: 9,
+            ..Default::default()
+        };
+        message.set_content(vec![5; 20]);
+        let mut data = Vec::new();
+        message.try_serialize(&mut data).unwrap();
+        // What `send_message_sized` allocated for 20 bytes under the version 6 layout
+        let v6_sized = MIN_SIZED_MESSAGE_ACCOUNT_SIZE - 1 + 20;
+        assert!(data.len() <= v6_sized && v6_sized <= sized_message_space(20));
+        data.resize(v6_sized, 0);
+
+        let upgraded = read_versioned_message(&data).unwrap();
+        assert_eq!(upgraded.version, MESSAGE_VERSION);
+        assert_eq!(upgraded.message_id, 9);
+        assert_eq!(upgraded.encrypted_content, vec![5; 20]);
+        assert_eq!(upgraded.content_hash, message.content_hash);
+    }
+
+    #[test]
+    fn sized_messages_only_take_edits_that_fit() {
+        assert_eq!(message_content_capacity(sized_message_space(20)), 20);
+        assert_eq!(message_content_capacity(sized_message_space(1)), 1);
+        assert!(message_content_capacity(MESSAGE_ACCOUNT_SIZE) >= MAX_MESSAGE_LEN);
+        // A version 6 sized account has less room than a current one
+        let v6_sized = MIN_SIZED_MESSAGE_ACCOUNT_SIZE - 1 + 20;
+        assert!(message_content_capacity(v6_sized) < 20);
+    }
+
+    #[test]
+    fn deadlines_must_lie_within_the_next_year() {
+        let now = 1_700_000_000;
+        assert_eq!(
+            validate_future_deadline(now - 1, now).unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::DeadlineInPast)
+        );
+        assert!(validate_future_deadline(now, now).is_ok());
+        assert!(validate_future_deadline(now + MAX_DEADLINE_SECS, now).is_ok());
+        assert_eq!(
+            validate_future_deadline(now + 2 * MAX_DEADLINE_SECS, now).unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::DeadlineTooFar)
+        );
+    }
+
+    /// Lays out ed25519 program data the way `Ed25519Program` clients do:
+    /// header, public key, signature, then the message.
+    fn ed25519_data(pubkey: &[u8; 32], signature: &[u8; 64], content: &[u8]) -> Vec<u8> {
+        let (key_at, sig_at, msg_at) = (16u16, 16 + 32, 16 + 32 + 64);
+        let inline = u16::MAX;
+        let header = [sig_at, inline, key_at, inline, msg_at, content.len() as u16, inline];
+        let mut data = vec![1, 0];
+        for field in header {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(pubkey);
+        data.extend_from_slice(signature);
+        data.extend_from_slice(content);
+        data
+    }
+
+    #[test]
+    fn ed25519_ix_must_cover_key_signature_and_content() {
+        let (pubkey, signature, content) = ([3; 32], [5; 64], b"ciphertext");
+        let data = ed25519_data(&pubkey, &signature, content);
+
+        assert!(ed25519_ix_covers(&data, &pubkey, content, &signature));
+        assert!(!ed25519_ix_covers(&data, &[4; 32], content, &signature));
+        assert!(!ed25519_ix_covers(&data, &pubkey, b"tampered!!", &signature));
+        assert!(!ed25519_ix_covers(&data, &pubkey, content, &[6; 64]));
+        assert!(!ed25519_ix_covers(&data[..10], &pubkey, content, &signature));
+    }
+
+    fn padded(name: &[u8]) -> [u8; 32] {
+        let mut padded = [0; 32];
+        padded[..name.len()].copy_from_slice(name);
+        padded
+    }
+
+    #[test]
+    fn usernames_are_lowercase_alphanumeric_and_right_padded() {
+        assert!(validate_username(&padded(b"alice_99")).is_ok());
+        assert!(validate_username(&[b'a'; 32]).is_ok());
+
+        let mut gap = padded(b"al");
+        gap[3] = b'x';
+        for name in [padded(b""), padded(b"Alice"), padded(b"al ice"), gap] {
+            assert_eq!(
+                validate_username(&name).unwrap_err(),
+                anchor_lang::error::Error::from(ChatError::InvalidUsername)
+            );
+        }
+    }
+
+    #[test]
+    fn edit_history_keeps_the_latest_hashes() {
+        let mut message = Message {
+            encrypted_content: vec![0],
+            ..Default::default()
+        };
+        for edit in 1..=7u8 {
+            message.record_edit(vec![edit], i64::from(edit));
+        }
+
+        assert_eq!(message.edit_count, 7);
+        assert_eq!(message.encrypted_content, vec![7]);
+        assert_eq!(message.edited_at, Some(7));
+        let expected: Vec<[u8; 32]> = (2..=6u8)
+            .map(|old| keccak::hash(&[old]).to_bytes())
+            .collect();
+        assert_eq!(message.prev_content_hashes, expected);
+    }
+
+    #[test]
+    fn stale_presence_reads_as_offline() {
+        let presence = Presence {
+            status: PRESENCE_AWAY,
+            last_seen: 1_000,
+        };
+        assert_eq!(presence.effective_status(1_000 + PRESENCE_STALE_SECS), PRESENCE_AWAY);
+        assert_eq!(
+            presence.effective_status(1_001 + PRESENCE_STALE_SECS),
+            PRESENCE_OFFLINE
+        );
+    }
+
+    #[test]
+    fn recall_window_is_inclusive() {
+        let message = Message {
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        assert!(message.within_recall_window(1_000 + RECALL_WINDOW_SECS));
+        assert!(!message.within_recall_window(1_000 + RECALL_WINDOW_SECS + 1));
+    }
+
+    #[test]
+    fn chat_error_codes_are_stable() {
+        assert_eq!(u32::from(ChatError::UnauthorizedDeletion), 6000);
+        assert_eq!(u32::from(ChatError::EmptyMessage), 6003);
+        assert_eq!(u32::from(ChatError::InvalidSignature), 6031);
+        assert_eq!(u32::from(ChatError::UnknownAccountVersion), 6057);
+        assert_eq!(u32::from(ChatError::InsufficientBalance), 6068);
+        assert_eq!(u32::from(ChatError::InvalidCompressedPayload), 6069);
+        assert_eq!(u32::from(ChatError::SealedKeyCountMismatch), 6070);
+    }
+
+    #[test]
+    fn pda_helpers_match_the_instruction_seeds() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (room, room_bump) = pda::chat_room_pda(&crate::ID, 3);
+        let seeds: &[&[u8]] = &[b"chat_room", &3u64.to_le_bytes(), &[room_bump]];
+        assert_eq!(Pubkey::create_program_address(seeds, &crate::ID), Ok(room));
+
+        let (message, bump) = pda::message_pda(&crate::ID, &room, 7);
+        let seeds: &[&[u8]] = &[b"message", room.as_ref(), &7u64.to_le_bytes(), &[bump]];
+        assert_eq!(Pubkey::create_program_address(seeds, &crate::ID), Ok(message));
+
+        let (first, second) = if a < b { (a, b) } else { (b, a) };
+        let expected = Pubkey::find_program_address(
+            &[b"conversation", first.as_ref(), second.as_ref()],
+            &crate::ID,
+        );
+        assert_eq!(pda::conversation_pda(&crate::ID, &a, &b), expected);
+        assert_eq!(pda::conversation_pda(&crate::ID, &b, &a), expected);
+
+        assert_eq!(
+            pda::inbox_pda(&crate::ID, &a),
+            Pubkey::find_program_address(&[b"inbox", a.as_ref()], &crate::ID)
+        );
+        assert_eq!(
+            pda::sent_marker_pda(&crate::ID, &a, &[1; CLIENT_NONCE_LEN]),
+            Pubkey::find_program_address(&[b"sent", a.as_ref(), &[1; CLIENT_NONCE_LEN]], &crate::ID)
+        );
+    }
+
+    #[test]
+    fn load_message_checked_rejects_other_accounts() {
+        let key = Pubkey::new_unique();
+        let mut message = Vec::new();
+        Message::default().try_serialize(&mut message).unwrap();
+        let mut inbox = Vec::new();
+        UserInbox::default().try_serialize(&mut inbox).unwrap();
+        let load = |mut data: Vec<u8>, owner: Pubkey| {
+            let mut lamports = 0;
+            let info = AccountInfo::new(
+                &key,
+                false,
+                true,
+                &mut lamports,
+                &mut data,
+                &owner,
+                false,
+                0,
+            );
+            util::load_message_checked(&info).map(|message| message.message_id)
+        };
+
+        assert_eq!(load(message.clone(), crate::ID).unwrap(), 0);
+        for (data, owner) in [(message, Pubkey::new_unique()), (inbox, crate::ID)] {
+            assert_eq!(
+                load(data, owner).unwrap_err(),
+                anchor_lang::error::Error::from(ChatError::InvalidAccount)
+            );
+        }
+    }
+
+    #[test]
+    fn slow_mode_holds_the_room_until_the_interval_passes() {
+        let mut room = ChatRoom {
+            slow_mode_secs: 30,
+            ..Default::default()
+        };
+        let mut sender_state = SenderState::default();
+        assert!(!room.in_slow_mode(1_000));
+        authorize_room_send(&mut room, 0, false, None, &mut sender_state, 1_000).unwrap();
+        assert!(room.in_slow_mode(1_029));
+        assert_eq!(
+            authorize_room_send(&mut room, 0, false, None, &mut sender_state, 1_029).unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::SlowMode)
+        );
+        assert!(!room.in_slow_mode(1_030));
+
+        // Messages that bypass the gate, like system notices, do not restart it
+        room.record_message(1_040).unwrap();
+        assert!(!room.in_slow_mode(1_040));
+        room.slow_mode_secs = 0;
+        assert!(!room.in_slow_mode(1_000));
+    }
+
+    #[test]
+    fn every_room_send_checks_the_sender_balance() {
+        let mut room = ChatRoom {
+            min_sender_balance: 1_000_000,
+            ..Default::default()
+        };
+        let mut sender_state = SenderState::default();
+        assert_eq!(
+            authorize_room_send(&mut room, 999_999, false, None, &mut sender_state, 1_000)
+                .unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::InsufficientBalance)
+        );
+        authorize_room_send(&mut room, 1_000_000, false, None, &mut sender_state, 1_000).unwrap();
+    }
+
+    #[test]
+    fn edit_window_is_inclusive() {
+        let message = Message {
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        assert!(message.within_edit_window(1_000));
+        assert!(message.within_edit_window(1_000 + EDIT_WINDOW_SECS));
+        assert!(!message.within_edit_window(1_000 + EDIT_WINDOW_SECS + 1));
+    }
+
+    #[test]
+    fn rate_limit_allows_up_to_the_cap_within_a_window() {
+        let mut state = SenderState::default();
+        state.record_send(1_000, 60, 2, 0).unwrap();
+        state.record_send(1_010, 60, 2, 0).unwrap();
+        assert_eq!(
+            state.record_send(1_020, 60, 2, 0).unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::RateLimited)
+        );
+    }
+
+    #[test]
+    fn rate_limit_resets_after_a_quiet_window() {
+        let mut state = SenderState::default();
+        state.record_send(1_000, 60, 1, 0).unwrap();
+        // One second short of the window is still limited...
+        assert!(state.record_send(1_059, 60, 1, 0).is_err());
+        // ...while a full quiet window restores the allowance.
+        state.record_send(1_060, 60, 1, 0).unwrap();
+    }
+
+    #[test]
+    fn rate_limit_enforces_the_minimum_interval() {
+        let mut state = SenderState::default();
+        state.record_send(1_000, 0, 0, 5).unwrap();
+        assert_eq!(
+            state.record_send(1_004, 0, 0, 5).unwrap_err(),
+            anchor_lang::error::Error::from(ChatError::RateLimited)
+        );
+        state.record_send(1_005, 0, 0, 5).unwrap();
+    }
+}
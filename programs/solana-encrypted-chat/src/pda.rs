@@ -0,0 +1,156 @@
+//! Addresses of the program's PDAs, derived from the same seeds the
+//! instructions check, so clients and integration tests need not copy them.
+//!
+//! Each helper returns the address and its canonical bump. Arguments are
+//! the seed values in seed order; `program_id` is normally `crate::ID`.
+
+use anchor_lang::prelude::Pubkey;
+
+use crate::CLIENT_NONCE_LEN;
+
+fn find(seeds: &[&[u8]], program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(seeds, program_id)
+}
+
+pub fn chat_room_pda(program_id: &Pubkey, room_id: u64) -> (Pubkey, u8) {
+    find(&[b"chat_room", &room_id.to_le_bytes()], program_id)
+}
+
+/// `room` is the `ChatRoom` or, for direct messages, the `Conversation`.
+pub fn message_pda(program_id: &Pubkey, room: &Pubkey, message_id: u64) -> (Pubkey, u8) {
+    find(&[b"message", room.as_ref(), &message_id.to_le_bytes()], program_id)
+}
+
+pub fn chunk_pda(program_id: &Pubkey, message: &Pubkey, index: u16) -> (Pubkey, u8) {
+    find(&[b"chunk", message.as_ref(), &index.to_le_bytes()], program_id)
+}
+
+pub fn inbox_pda(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"inbox", owner.as_ref()], program_id)
+}
+
+pub fn sender_state_pda(program_id: &Pubkey, room: &Pubkey, sender: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"sender", room.as_ref(), sender.as_ref()], program_id)
+}
+
+/// The participants may be given in either order; the seeds take them in
+/// the ascending order `init_conversation` requires.
+pub fn conversation_pda(program_id: &Pubkey, a: &Pubkey, b: &Pubkey) -> (Pubkey, u8) {
+    let (first, second) = if a < b { (a, b) } else { (b, a) };
+    find(&[b"conversation", first.as_ref(), second.as_ref()], program_id)
+}
+
+/// `conversation` is a `ChatRoom` or `Conversation`.
+pub fn conversation_state_pda(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    conversation: &Pubkey,
+) -> (Pubkey, u8) {
+    find(&[b"conv_state", user.as_ref(), conversation.as_ref()], program_id)
+}
+
+/// `conversation` is a `ChatRoom` or `Conversation`.
+pub fn read_cursor_pda(program_id: &Pubkey, user: &Pubkey, conversation: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"cursor", user.as_ref(), conversation.as_ref()], program_id)
+}
+
+pub fn session_pda(program_id: &Pubkey, conversation: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"session", conversation.as_ref()], program_id)
+}
+
+pub fn typing_state_pda(program_id: &Pubkey, conversation: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"typing", conversation.as_ref(), user.as_ref()], program_id)
+}
+
+pub fn key_registry_pda(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"key", owner.as_ref()], program_id)
+}
+
+pub fn block_pda(program_id: &Pubkey, blocker: &Pubkey, blocked: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"block", blocker.as_ref(), blocked.as_ref()], program_id)
+}
+
+pub fn mute_pda(program_id: &Pubkey, muter: &Pubkey, muted: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"mute", muter.as_ref(), muted.as_ref()], program_id)
+}
+
+pub fn membership_pda(program_id: &Pubkey, room: &Pubkey, member: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"member", room.as_ref(), member.as_ref()], program_id)
+}
+
+pub fn invite_pda(program_id: &Pubkey, room: &Pubkey, code_hash: &[u8; 32]) -> (Pubkey, u8) {
+    find(&[b"invite", room.as_ref(), code_hash], program_id)
+}
+
+pub fn pinned_messages_pda(program_id: &Pubkey, room: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"pinned", room.as_ref()], program_id)
+}
+
+pub fn reactions_pda(program_id: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"reactions", message.as_ref()], program_id)
+}
+
+pub fn report_pda(program_id: &Pubkey, message: &Pubkey, reporter: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"report", message.as_ref(), reporter.as_ref()], program_id)
+}
+
+pub fn hidden_message_pda(program_id: &Pubkey, user: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"hidden", user.as_ref(), message.as_ref()], program_id)
+}
+
+pub fn starred_message_pda(program_id: &Pubkey, user: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"star", user.as_ref(), message.as_ref()], program_id)
+}
+
+pub fn tip_escrow_pda(program_id: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"tip", message.as_ref()], program_id)
+}
+
+pub fn token_tip_escrow_pda(program_id: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"token_tip", message.as_ref()], program_id)
+}
+
+/// The token account holding a `TokenTipEscrow`'s tokens.
+pub fn token_tip_vault_pda(program_id: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"token_tip_vault", message.as_ref()], program_id)
+}
+
+pub fn presence_pda(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"presence", user.as_ref()], program_id)
+}
+
+pub fn contacts_pda(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"contacts", owner.as_ref()], program_id)
+}
+
+pub fn username_pda(program_id: &Pubkey, name: &[u8; 32]) -> (Pubkey, u8) {
+    find(&[b"username", name], program_id)
+}
+
+pub fn user_profile_pda(program_id: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"profile", owner.as_ref()], program_id)
+}
+
+pub fn draft_pda(program_id: &Pubkey, owner: &Pubkey, recipient: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"draft", owner.as_ref(), recipient.as_ref()], program_id)
+}
+
+pub fn user_stats_pda(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"stats", user.as_ref()], program_id)
+}
+
+pub fn user_settings_pda(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"settings", user.as_ref()], program_id)
+}
+
+pub fn sent_marker_pda(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    client_nonce: &[u8; CLIENT_NONCE_LEN],
+) -> (Pubkey, u8) {
+    find(&[b"sent", sender.as_ref(), client_nonce], program_id)
+}
+
+pub fn read_marker_pda(program_id: &Pubkey, recipient: &Pubkey, message: &Pubkey) -> (Pubkey, u8) {
+    find(&[b"read", recipient.as_ref(), message.as_ref()], program_id)
+}
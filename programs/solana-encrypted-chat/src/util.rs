@@ -0,0 +1,24 @@
+//! Checked loading of accounts that arrive through `remaining_accounts`,
+//! which Anchor passes through without any owner or type checks.
+//!
+//! `reap_expired_batch` and `purge_messages` load existing messages this
+//! way. `batch_send` creates its messages at derived addresses instead, and
+//! reads inboxes through `load_inbox`, so it has nothing to load here.
+
+use anchor_lang::prelude::*;
+
+use crate::*;
+
+/// Loads a `Message`, first confirming the account is owned by this program
+/// and starts with the `Message` discriminator. Anything else, including an
+/// account of another of the program's types, is `ChatError::InvalidAccount`.
+pub fn load_message_checked<'info>(
+    info: &'info AccountInfo<'info>,
+) -> Result<Account<'info, Message>> {
+    require_keys_eq!(*info.owner, crate::ID, ChatError::InvalidAccount);
+    require!(
+        info.try_borrow_data()?.starts_with(Message::DISCRIMINATOR),
+        ChatError::InvalidAccount
+    );
+    Account::try_from(info)
+}
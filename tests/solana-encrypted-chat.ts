@@ -3,6 +3,18 @@ import { Program } from "@coral-xyz/anchor";
 import { SolanaEncryptedChat } from "../target/types/solana_encrypted_chat";
 import { expect } from "chai";
 import { PublicKey, Keypair } from "@solana/web3.js";
+import {
+  createMint,
+  getAccount,
+  getAssociatedTokenAddressSync,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
+} from "@solana/spl-token";
+import { keccak_256 } from "@noble/hashes/sha3";
+import { randomBytes } from "crypto";
+import nacl from "tweetnacl";
 
 describe("solana-encrypted-chat", () => {
   // Configure the client to use the local cluster.
@@ -18,6 +30,291 @@ describe("solana-encrypted-chat", () => {
   let chatRoomPda: PublicKey;
   let chatRoomBump: number;
 
+  const roomPda = (roomId: number | anchor.BN) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("chat_room"), new anchor.BN(roomId).toArrayLike(Buffer, "le", 8)],
+      program.programId
+    )[0];
+
+  const messagePda = (messageId: number | anchor.BN, room: PublicKey = chatRoomPda) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("message"),
+        room.toBuffer(),
+        new anchor.BN(messageId).toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    )[0];
+
+  // Conversation seeds take the two participants in ascending byte order.
+  const sortParticipants = (a: PublicKey, b: PublicKey): [PublicKey, PublicKey] =>
+    Buffer.compare(a.toBuffer(), b.toBuffer()) < 0 ? [a, b] : [b, a];
+
+  const conversationPda = (a: PublicKey, b: PublicKey) => {
+    const [first, second] = sortParticipants(a, b);
+    return PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), first.toBuffer(), second.toBuffer()],
+      program.programId
+    )[0];
+  };
+
+  const keyRegistryPda = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync([Buffer.from("key"), owner.toBuffer()], program.programId)[0];
+
+  const senderStatePda = (room: PublicKey, sender: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("sender"), room.toBuffer(), sender.toBuffer()],
+      program.programId
+    )[0];
+
+  const blockPda = (blocker: PublicKey, blocked: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("block"), blocker.toBuffer(), blocked.toBuffer()],
+      program.programId
+    )[0];
+
+  const reactionsPda = (message: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("reactions"), message.toBuffer()],
+      program.programId
+    )[0];
+
+  const chunkPda = (message: PublicKey, index: number) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("chunk"), message.toBuffer(), new anchor.BN(index).toArrayLike(Buffer, "le", 2)],
+      program.programId
+    )[0];
+
+  // Rooms created by tests are unthrottled unless a test opts in.
+  const defaultRoomConfig = {
+    rateLimitWindowSecs: 0,
+    rateLimitMaxMessages: 0,
+    gated: false,
+    maxMessageLen: 512,
+    defaultTtlSecs: null as number | null,
+    minSenderBalance: new anchor.BN(0),
+    rateLimitMinIntervalSecs: 0,
+  };
+
+  const membershipPda = (room: PublicKey, member: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("member"), room.toBuffer(), member.toBuffer()],
+      program.programId
+    )[0];
+
+  const tipEscrowPda = (message: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("tip"), message.toBuffer()],
+      program.programId
+    )[0];
+
+  const tokenTipPdas = (message: PublicKey) => ({
+    tokenTipEscrow: PublicKey.findProgramAddressSync(
+      [Buffer.from("token_tip"), message.toBuffer()],
+      program.programId
+    )[0],
+    vault: PublicKey.findProgramAddressSync(
+      [Buffer.from("token_tip_vault"), message.toBuffer()],
+      program.programId
+    )[0],
+  });
+
+  const inboxPda = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("inbox"), owner.toBuffer()],
+      program.programId
+    )[0];
+
+  const statsPda = (user: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("stats"), user.toBuffer()],
+      program.programId
+    )[0];
+
+  const readMarkerPda = (recipient: PublicKey, message: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("read"), recipient.toBuffer(), message.toBuffer()],
+      program.programId
+    )[0];
+
+  const settingsPda = (user: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("settings"), user.toBuffer()],
+      program.programId
+    )[0];
+
+  const sentMarkerPda = (sender: PublicKey, clientNonce: Buffer) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("sent"), sender.toBuffer(), clientNonce],
+      program.programId
+    )[0];
+
+  const chainTime = async () =>
+    provider.connection.getBlockTime(await provider.connection.getSlot());
+
+  const airdrop = async (recipient: PublicKey, sol = 1) => {
+    const signature = await provider.connection.requestAirdrop(
+      recipient,
+      sol * anchor.web3.LAMPORTS_PER_SOL
+    );
+    await provider.connection.confirmTransaction(signature);
+  };
+
+  const initializeRoom = async (
+    roomId: number,
+    authority: Keypair = alice,
+    config: Partial<typeof defaultRoomConfig> = {}
+  ) => {
+    const chatRoom = roomPda(roomId);
+    await program.methods
+      .initialize(new anchor.BN(roomId), { ...defaultRoomConfig, ...config })
+      .accounts({
+        chatRoom,
+        user: authority.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([authority])
+      .rpc();
+    return chatRoom;
+  };
+
+  const addMember = (room: PublicKey, member: PublicKey, authority: Keypair = alice) =>
+    program.methods
+      .addMember(member)
+      .accounts({
+        chatRoom: room,
+        membership: membershipPda(room, member),
+        authority: authority.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([authority])
+      .rpc();
+
+  const proposeAuthority = (room: PublicKey, newAuthority: PublicKey, authority: Keypair = alice) =>
+    program.methods
+      .proposeAuthority(newAuthority)
+      .accounts({ chatRoom: room, authority: authority.publicKey })
+      .signers([authority])
+      .rpc();
+
+  const acceptAuthority = (room: PublicKey, newAuthority: Keypair) =>
+    program.methods
+      .acceptAuthority()
+      .accounts({ chatRoom: room, newAuthority: newAuthority.publicKey })
+      .signers([newAuthority])
+      .rpc();
+
+  // The `SendMessage` accounts, also nested by the tipping variants.
+  const sendAccounts = (
+    sender: PublicKey,
+    recipient: PublicKey,
+    chatRoom: PublicKey,
+    message: PublicKey,
+    asMember = false,
+    payer = sender
+  ) => ({
+    message,
+    chatRoom,
+    inbox: inboxPda(recipient),
+    senderState: senderStatePda(chatRoom, sender),
+    block: blockPda(recipient, sender),
+    senderStats: statsPda(sender),
+    recipientStats: statsPda(recipient),
+    membership: asMember ? membershipPda(chatRoom, sender) : null,
+    keyRegistry: null,
+    instructions: null,
+    parent: null,
+    sentMarker: null,
+    sender,
+    payer,
+    systemProgram: anchor.web3.SystemProgram.programId,
+  });
+
+  // Sends `content` from `sender` to `recipient` and returns the new message PDA and id.
+  const sendMessage = async (
+    sender: Keypair,
+    recipient: PublicKey,
+    content: Buffer,
+    {
+      algorithm = 0,
+      nonce = randomBytes(24),
+      replyTo = null,
+      expiresAt = null,
+      roomId = 0,
+      parent = replyTo === null ? null : messagePda(replyTo, roomPda(roomId)),
+      asMember = false,
+      attachment = null,
+      priority = 0,
+      payer = sender,
+      clientNonce = null,
+      viewOnce = false,
+      quotedContentHash = null,
+      compressed = false,
+    }: {
+      algorithm?: number;
+      nonce?: Buffer;
+      replyTo?: number | null;
+      expiresAt?: number | null;
+      roomId?: number;
+      parent?: PublicKey | null;
+      asMember?: boolean;
+      attachment?: { kind: number; cid: string } | null;
+      priority?: number;
+      payer?: Keypair;
+      clientNonce?: Buffer | null;
+      viewOnce?: boolean;
+      quotedContentHash?: Buffer | null;
+      compressed?: boolean;
+    } = {}
+  ) => {
+    const chatRoom = roomPda(roomId);
+    const { messageCount } = await program.account.chatRoom.fetch(chatRoom);
+    const message = messagePda(messageCount, chatRoom);
+    await program.methods
+      .sendMessage(
+        new anchor.BN(roomId),
+        Array.from(content),
+        recipient,
+        algorithm,
+        Array.from(nonce),
+        replyTo === null ? null : new anchor.BN(replyTo),
+        expiresAt === null ? null : new anchor.BN(expiresAt),
+        Array.from(Buffer.alloc(64)),
+        attachment,
+        priority,
+        clientNonce === null ? null : Array.from(clientNonce),
+        viewOnce,
+        quotedContentHash === null ? null : Array.from(quotedContentHash),
+        compressed
+      )
+      .accounts({
+        ...sendAccounts(sender.publicKey, recipient, chatRoom, message, asMember, payer.publicKey),
+        parent,
+        sentMarker: clientNonce === null ? null : sentMarkerPda(sender.publicKey, clientNonce),
+      })
+      .signers(payer === sender ? [sender] : [sender, payer])
+      .rpc();
+    return { messagePda: message, messageId: messageCount.toNumber() };
+  };
+
+  const markDelivered = (message: PublicKey, recipient: Keypair) =>
+    program.methods
+      .markDelivered()
+      .accounts({ message, recipient: recipient.publicKey })
+      .signers([recipient])
+      .rpc();
+
+  // Asserts that a transaction fails with the given ChatError variant name.
+  const expectChatError = async (promise: Promise<unknown>, code: string) => {
+    try {
+      await promise;
+    } catch (error) {
+      expect(error.error?.errorCode?.code).to.equal(code);
+      return;
+    }
+    expect.fail(`Expected transaction to fail with ${code}`);
+  };
+
   before(async () => {
     // Airdrop to test accounts
     const airdropSignature = await provider.connection.requestAirdrop(
@@ -25,10 +322,11 @@ describe("solana-encrypted-chat", () => {
       2 * anchor.web3.LAMPORTS_PER_SOL
     );
     await provider.connection.confirmTransaction(airdropSignature);
+    await airdrop(bob.publicKey);
 
-    // Calculate chat room PDA
+    // Calculate the legacy global chat room PDA (room id 0)
     [chatRoomPda, chatRoomBump] = await PublicKey.findProgramAddress(
-      [Buffer.from("chat_room")],
+      [Buffer.from("chat_room"), new anchor.BN(0).toArrayLike(Buffer, "le", 8)],
       program.programId
     );
   });
@@ -36,7 +334,7 @@ describe("solana-encrypted-chat", () => {
   it("Initializes the chat room", async () => {
     try {
       const tx = await program.methods
-        .initialize()
+        .initialize(new anchor.BN(0), defaultRoomConfig)
         .accounts({
           chatRoom: chatRoomPda,
           user: alice.publicKey,
@@ -67,6 +365,7 @@ describe("solana-encrypted-chat", () => {
     const [messagePda] = await PublicKey.findProgramAddress(
       [
         Buffer.from("message"),
+        chatRoomPda.toBuffer(),
         messageCount.toArrayLike(Buffer, "le", 8)
       ],
       program.programId
@@ -74,13 +373,36 @@ describe("solana-encrypted-chat", () => {
 
     const tx = await program.methods
       .sendMessage(
+        new anchor.BN(0),
         Array.from(mockEncryptedMessage),
-        bob.publicKey
+        bob.publicKey,
+        0,
+        Array.from(randomBytes(24)),
+        null,
+        null,
+        Array.from(Buffer.alloc(64)),
+        null,
+        0,
+        null,
+        false,
+        null,
+        false
       )
       .accounts({
         message: messagePda,
         chatRoom: chatRoomPda,
+        inbox: inboxPda(bob.publicKey),
+        senderState: senderStatePda(chatRoomPda, alice.publicKey),
+        block: blockPda(bob.publicKey, alice.publicKey),
+        senderStats: statsPda(alice.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        membership: null,
+        keyRegistry: null,
+        instructions: null,
+        parent: null,
+        sentMarker: null,
         sender: alice.publicKey,
+        payer: alice.publicKey,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([alice])
@@ -132,6 +454,7 @@ describe("solana-encrypted-chat", () => {
     const [messagePda] = await PublicKey.findProgramAddress(
       [
         Buffer.from("message"),
+        chatRoomPda.toBuffer(),
         messageCount.toArrayLike(Buffer, "le", 8)
       ],
       program.programId
@@ -141,13 +464,36 @@ describe("solana-encrypted-chat", () => {
     
     await program.methods
       .sendMessage(
+        new anchor.BN(0),
         Array.from(secondMessage),
-        bob.publicKey
+        bob.publicKey,
+        0,
+        Array.from(randomBytes(24)),
+        null,
+        null,
+        Array.from(Buffer.alloc(64)),
+        null,
+        0,
+        null,
+        false,
+        null,
+        false
       )
       .accounts({
         message: messagePda,
         chatRoom: chatRoomPda,
+        inbox: inboxPda(bob.publicKey),
+        senderState: senderStatePda(chatRoomPda, alice.publicKey),
+        block: blockPda(bob.publicKey, alice.publicKey),
+        senderStats: statsPda(alice.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        membership: null,
+        keyRegistry: null,
+        instructions: null,
+        parent: null,
+        sentMarker: null,
         sender: alice.publicKey,
+        payer: alice.publicKey,
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .signers([alice])
@@ -157,4 +503,2797 @@ describe("solana-encrypted-chat", () => {
     const updatedChatRoom = await program.account.chatRoom.fetch(chatRoomPda);
     expect(updatedChatRoom.messageCount.toNumber()).to.equal(2);
   });
+
+  it("Deletes a message and refunds rent to the sender", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("Message to delete"));
+
+    // Bob is the recipient, not the sender, so the delete must be rejected
+    await expectChatError(
+      program.methods
+        .deleteMessage()
+        .accounts({ message: messagePda, parent: null, sender: bob.publicKey })
+        .signers([bob])
+        .rpc(),
+      "UnauthorizedDeletion"
+    );
+
+    const balanceBefore = await provider.connection.getBalance(alice.publicKey);
+
+    await program.methods
+      .deleteMessage()
+      .accounts({ message: messagePda, parent: null, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    const closed = await provider.connection.getAccountInfo(messagePda);
+    expect(closed).to.be.null;
+
+    const balanceAfter = await provider.connection.getBalance(alice.publicKey);
+    expect(balanceAfter).to.be.greaterThan(balanceBefore);
+  });
+
+  it("Deletes an older message after the counter has advanced", async () => {
+    const firstMessagePda = messagePda(0);
+
+    await program.methods
+      .deleteMessage()
+      .accounts({ message: firstMessagePda, parent: null, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    expect(await provider.connection.getAccountInfo(firstMessagePda)).to.be.null;
+  });
+
+  it("Edits a message and records the edit timestamp", async () => {
+    const secondMessagePda = messagePda(1);
+    const original = await program.account.message.fetch(secondMessagePda);
+    expect(original.editedAt).to.be.null;
+
+    const correction = Buffer.from("Corrected encrypted message");
+    await program.methods
+      .editMessage(Array.from(correction))
+      .accounts({ message: secondMessagePda, room: chatRoomPda, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    const edited = await program.account.message.fetch(secondMessagePda);
+    expect(Buffer.from(edited.encryptedContent)).to.deep.equal(correction);
+    expect(edited.editedAt).to.not.be.null;
+    expect(edited.timestamp.toNumber()).to.equal(original.timestamp.toNumber());
+
+    await expectChatError(
+      program.methods
+        .editMessage(Array.from(Buffer.alloc(513, 1)))
+        .accounts({ message: secondMessagePda, room: chatRoomPda, sender: alice.publicKey })
+        .signers([alice])
+        .rpc(),
+      "MessageTooLong"
+    );
+
+    await expectChatError(
+      program.methods
+        .editMessage(Array.from(correction))
+        .accounts({ message: secondMessagePda, room: chatRoomPda, sender: bob.publicKey })
+        .signers([bob])
+        .rpc(),
+      "UnauthorizedEdit"
+    );
+  });
+
+  it("Emits a MessageSent event", async () => {
+    let listener: number;
+    const event = new Promise<any>((resolve) => {
+      listener = program.addEventListener("messageSent", (e) => resolve(e));
+    });
+
+    const { messageId } = await sendMessage(alice, bob.publicKey, Buffer.from("Evented message"));
+
+    const sent = await event;
+    await program.removeEventListener(listener);
+
+    expect(sent.messageId.toNumber()).to.equal(messageId);
+    expect(sent.sender.toString()).to.equal(alice.publicKey.toString());
+    expect(sent.recipient.toString()).to.equal(bob.publicKey.toString());
+  });
+
+  it("Indexes received messages in the recipient's inbox", async () => {
+    const carol = Keypair.generate();
+    const { messageId } = await sendMessage(alice, carol.publicKey, Buffer.from("Hi Carol"));
+
+    const inbox = await program.account.userInbox.fetch(inboxPda(carol.publicKey));
+    expect(inbox.owner.toString()).to.equal(carol.publicKey.toString());
+    expect(inbox.messageCount.toNumber()).to.equal(1);
+    const entries = inbox.recentMessages.map((entry) => entry.messageId.toNumber());
+    expect(entries).to.deep.equal([messageId]);
+  });
+
+  it("Initializes an inbox ahead of the first message", async () => {
+    const dave = Keypair.generate();
+    await airdrop(dave.publicKey);
+
+    await program.methods
+      .initInbox()
+      .accounts({
+        inbox: inboxPda(dave.publicKey),
+        owner: dave.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([dave])
+      .rpc();
+
+    const inbox = await program.account.userInbox.fetch(inboxPda(dave.publicKey));
+    expect(inbox.owner.toString()).to.equal(dave.publicKey.toString());
+    expect(inbox.messageCount.toNumber()).to.equal(0);
+  });
+
+  it("Rejects empty and oversized messages", async () => {
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.alloc(0)),
+      "EmptyMessage"
+    );
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.alloc(513, 1)),
+      "MessageTooLong"
+    );
+  });
+
+  it("Acknowledges a message with a read receipt", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("Read me"));
+
+    await expectChatError(
+      program.methods
+        .acknowledgeMessage()
+        .accounts({
+          message: messagePda,
+          inbox: inboxPda(alice.publicKey),
+          recipientStats: statsPda(alice.publicKey),
+          settings: settingsPda(alice.publicKey),
+          readMarker: readMarkerPda(alice.publicKey, messagePda),
+          recipient: alice.publicKey,
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc(),
+      "NotRecipient"
+    );
+
+    await markDelivered(messagePda, bob);
+    await program.methods
+      .acknowledgeMessage()
+      .accounts({
+        message: messagePda,
+        inbox: inboxPda(bob.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        settings: settingsPda(bob.publicKey),
+        readMarker: readMarkerPda(bob.publicKey, messagePda),
+        recipient: bob.publicKey,
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const message = await program.account.message.fetch(messagePda);
+    expect(message.readAt).to.not.be.null;
+
+    await expectChatError(
+      program.methods
+        .acknowledgeMessage()
+        .accounts({
+          message: messagePda,
+          inbox: inboxPda(bob.publicKey),
+          recipientStats: statsPda(bob.publicKey),
+          settings: settingsPda(bob.publicKey),
+          readMarker: readMarkerPda(bob.publicKey, messagePda),
+          recipient: bob.publicKey,
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc(),
+      "AlreadyRead"
+    );
+  });
+
+  it("Stores the cipher suite and rejects unknown ones", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("chacha"), {
+      algorithm: 1,
+    });
+    const message = await program.account.message.fetch(messagePda);
+    expect(message.algorithm).to.equal(1);
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("mystery"), { algorithm: 42 }),
+      "UnknownAlgorithm"
+    );
+  });
+
+  it("Round-trips the message nonce and rejects an all-zero one", async () => {
+    const nonce = randomBytes(24);
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("nonced"), {
+      nonce,
+    });
+    const message = await program.account.message.fetch(messagePda);
+    expect(Buffer.from(message.nonce)).to.deep.equal(nonce);
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("zeroed"), { nonce: Buffer.alloc(24) }),
+      "InvalidNonce"
+    );
+  });
+
+  it("Threads replies and rejects replies to future messages", async () => {
+    const parent = await sendMessage(alice, bob.publicKey, Buffer.from("parent"));
+    const reply = await sendMessage(bob, alice.publicKey, Buffer.from("reply"), {
+      replyTo: parent.messageId,
+    });
+
+    const message = await program.account.message.fetch(reply.messagePda);
+    expect(message.replyTo.toNumber()).to.equal(parent.messageId);
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("from the future"), {
+        replyTo: reply.messageId + 100,
+        parent: parent.messagePda,
+      }),
+      "InvalidReplyTarget"
+    );
+  });
+
+  it("Keeps independent counters per room", async () => {
+    const roomId = 7;
+    const room = await initializeRoom(roomId);
+
+    const globalBefore = await program.account.chatRoom.fetch(chatRoomPda);
+    const { messagePda: roomMessage, messageId } = await sendMessage(
+      alice,
+      bob.publicKey,
+      Buffer.from("room seven"),
+      { roomId }
+    );
+    expect(messageId).to.equal(0);
+
+    const message = await program.account.message.fetch(roomMessage);
+    expect(message.room.toString()).to.equal(room.toString());
+
+    const roomAccount = await program.account.chatRoom.fetch(room);
+    expect(roomAccount.roomId.toNumber()).to.equal(roomId);
+    expect(roomAccount.messageCount.toNumber()).to.equal(1);
+
+    const globalAfter = await program.account.chatRoom.fetch(chatRoomPda);
+    expect(globalAfter.messageCount.toNumber()).to.equal(globalBefore.messageCount.toNumber());
+  });
+
+  it("Opens a direct conversation and sends a DM", async () => {
+    const [first, second] = sortParticipants(alice.publicKey, bob.publicKey);
+    const conversation = conversationPda(alice.publicKey, bob.publicKey);
+
+    await expectChatError(
+      program.methods
+        .initConversation(second, first)
+        .accounts({
+          conversation: PublicKey.findProgramAddressSync(
+            [Buffer.from("conversation"), second.toBuffer(), first.toBuffer()],
+            program.programId
+          )[0],
+          initiator: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc(),
+      "UnsortedParticipants"
+    );
+
+    await program.methods
+      .initConversation(first, second)
+      .accounts({
+        conversation,
+        initiator: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const dm = messagePda(0, conversation);
+    await program.methods
+      .sendDirectMessage(Array.from(Buffer.from("just us")), 0, Array.from(randomBytes(24)), null)
+      .accounts({
+        message: dm,
+        conversation,
+        session: null,
+        senderStats: statsPda(bob.publicKey),
+        recipientStats: statsPda(alice.publicKey),
+        sender: bob.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const message = await program.account.message.fetch(dm);
+    expect(message.sender.toString()).to.equal(bob.publicKey.toString());
+    expect(message.recipient.toString()).to.equal(alice.publicKey.toString());
+    expect(message.room.toString()).to.equal(conversation.toString());
+
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+    await expectChatError(
+      program.methods
+        .sendDirectMessage(Array.from(Buffer.from("let me in")), 0, Array.from(randomBytes(24)), null)
+        .accounts({
+          message: messagePda(1, conversation),
+          conversation,
+          session: null,
+          senderStats: statsPda(carol.publicKey),
+          recipientStats: statsPda(alice.publicKey),
+          sender: carol.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([carol])
+        .rpc(),
+      "NotAParticipant"
+    );
+  });
+
+  it("Registers and rotates an encryption key", async () => {
+    const keyRegistry = keyRegistryPda(bob.publicKey);
+    const firstKey = randomBytes(32);
+    await program.methods
+      .registerKey(Array.from(firstKey))
+      .accounts({
+        keyRegistry,
+        owner: bob.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    let registry = await program.account.keyRegistry.fetch(keyRegistry);
+    expect(Buffer.from(registry.encryptionPubkey)).to.deep.equal(firstKey);
+
+    const rotatedKey = randomBytes(32);
+    await program.methods
+      .updateKey(Array.from(rotatedKey))
+      .accounts({ keyRegistry, owner: bob.publicKey })
+      .signers([bob])
+      .rpc();
+
+    registry = await program.account.keyRegistry.fetch(keyRegistry);
+    expect(Buffer.from(registry.encryptionPubkey)).to.deep.equal(rotatedKey);
+    expect(registry.owner.toString()).to.equal(bob.publicKey.toString());
+  });
+
+  it("Lets only the authority close a chat room", async () => {
+    const room = await initializeRoom(8);
+    const stored = await program.account.chatRoom.fetch(room);
+    expect(stored.authority.toString()).to.equal(alice.publicKey.toString());
+
+    await expectChatError(
+      program.methods
+        .closeChatRoom()
+        .accounts({ chatRoom: room, authority: bob.publicKey })
+        .signers([bob])
+        .rpc(),
+      "Unauthorized"
+    );
+
+    await program.methods
+      .closeChatRoom()
+      .accounts({ chatRoom: room, authority: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    expect(await provider.connection.getAccountInfo(room)).to.be.null;
+  });
+
+  it("Transfers room authority and revokes the old authority", async () => {
+    const room = await initializeRoom(9);
+
+    await proposeAuthority(room, bob.publicKey);
+    // Proposing alone changes nothing
+    let stored = await program.account.chatRoom.fetch(room);
+    expect(stored.authority.toString()).to.equal(alice.publicKey.toString());
+    expect(stored.pendingAuthority.toString()).to.equal(bob.publicKey.toString());
+
+    await acceptAuthority(room, bob);
+    stored = await program.account.chatRoom.fetch(room);
+    expect(stored.authority.toString()).to.equal(bob.publicKey.toString());
+    expect(stored.pendingAuthority).to.be.null;
+
+    // The previous authority can no longer administer the room
+    await expectChatError(proposeAuthority(room, alice.publicKey), "Unauthorized");
+    await expectChatError(
+      program.methods
+        .closeChatRoom()
+        .accounts({ chatRoom: room, authority: alice.publicKey })
+        .signers([alice])
+        .rpc(),
+      "Unauthorized"
+    );
+
+    await program.methods
+      .closeChatRoom()
+      .accounts({ chatRoom: room, authority: bob.publicKey })
+      .signers([bob])
+      .rpc();
+  });
+
+  it("Blocks new sends while a room is paused", async () => {
+    const roomId = 10;
+    const room = await initializeRoom(roomId);
+    const { messagePda: existing } = await sendMessage(alice, bob.publicKey, Buffer.from("before"), {
+      roomId,
+    });
+
+    await program.methods
+      .pauseRoom()
+      .accounts({ chatRoom: room, moderatorMembership: null, moderator: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("during"), { roomId }),
+      "RoomPaused"
+    );
+
+    // Existing messages can still be read and deleted
+    await program.account.message.fetch(existing);
+    await program.methods
+      .deleteMessage()
+      .accounts({ message: existing, parent: null, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    await program.methods
+      .unpauseRoom()
+      .accounts({ chatRoom: room, moderatorMembership: null, moderator: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    await sendMessage(alice, bob.publicKey, Buffer.from("after"), { roomId });
+  });
+
+  it("Rate limits senders at the room's configured cap", async () => {
+    const roomId = 11;
+    await initializeRoom(roomId, alice, { rateLimitWindowSecs: 3600, rateLimitMaxMessages: 2 });
+
+    // Just under and at the cap succeed
+    await sendMessage(alice, bob.publicKey, Buffer.from("one"), { roomId });
+    await sendMessage(alice, bob.publicKey, Buffer.from("two"), { roomId });
+
+    // Just over the cap is rejected
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("three"), { roomId }),
+      "RateLimited"
+    );
+
+    // Other senders have their own allowance
+    await sendMessage(bob, alice.publicKey, Buffer.from("bob's turn"), { roomId });
+  });
+
+  it("Rate limits senders who post again before the room's minimum interval", async () => {
+    const roomId = 27;
+    await initializeRoom(roomId, alice, { rateLimitMinIntervalSecs: 3600 });
+
+    await sendMessage(alice, bob.publicKey, Buffer.from("first"), { roomId });
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("too soon"), { roomId }),
+      "RateLimited"
+    );
+
+    // The gap is per sender, so bob is not held back by alice's message
+    await sendMessage(bob, alice.publicKey, Buffer.from("bob's first"), { roomId });
+  });
+
+  it("Rejects messages from blocked senders until unblocked", async () => {
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+    const block = blockPda(bob.publicKey, carol.publicKey);
+
+    await program.methods
+      .blockUser(carol.publicKey)
+      .accounts({
+        block,
+        blocker: bob.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    await expectChatError(
+      sendMessage(carol, bob.publicKey, Buffer.from("unwanted")),
+      "SenderBlocked"
+    );
+
+    // The block only applies in one direction
+    await sendMessage(bob, carol.publicKey, Buffer.from("still reachable"));
+
+    await program.methods
+      .unblockUser()
+      .accounts({ block, blocker: bob.publicKey })
+      .signers([bob])
+      .rpc();
+
+    await sendMessage(carol, bob.publicKey, Buffer.from("welcome back"));
+  });
+
+  it("Reaps expired messages and refuses to reap live ones", async () => {
+    const now = await chainTime();
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("stale"), { expiresAt: now - 60 }),
+      "DeadlineInPast"
+    );
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("forever"), {
+        expiresAt: now + 2 * 365 * 24 * 60 * 60,
+      }),
+      "DeadlineTooFar"
+    );
+
+    const expired = await sendMessage(alice, bob.publicKey, Buffer.from("ephemeral"), {
+      expiresAt: now + 2,
+    });
+    const live = await sendMessage(alice, bob.publicKey, Buffer.from("lasting"), {
+      expiresAt: now + 3600,
+    });
+
+    await expectChatError(
+      program.methods
+        .reapExpiredMessage()
+        .accounts({ message: live.messagePda, parent: null, sender: alice.publicKey })
+        .rpc(),
+      "NotExpired"
+    );
+
+    while ((await chainTime()) < now + 2) {
+      await new Promise((resolve) => setTimeout(resolve, 500));
+    }
+
+    // Anyone can reap; the provider wallet pays the fee and rent returns to Alice
+    const balanceBefore = await provider.connection.getBalance(alice.publicKey);
+    await program.methods
+      .reapExpiredMessage()
+      .accounts({ message: expired.messagePda, parent: null, sender: alice.publicKey })
+      .rpc();
+
+    expect(await provider.connection.getAccountInfo(expired.messagePda)).to.be.null;
+    expect(await provider.connection.getBalance(alice.publicKey)).to.be.greaterThan(balanceBefore);
+  });
+
+  it("Counts reactions per kind", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("react to me"));
+    const reactions = reactionsPda(messagePda);
+    const react = (reactor: Keypair, index: number) =>
+      program.methods
+        .addReaction(index)
+        .accounts({
+          message: messagePda,
+          reactions,
+          reactor: reactor.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([reactor])
+        .rpc();
+
+    await react(bob, 0);
+    await react(alice, 0);
+    await react(bob, 1);
+    await program.methods
+      .removeReaction(0)
+      .accounts({ message: messagePda, reactions, reactor: bob.publicKey })
+      .signers([bob])
+      .rpc();
+
+    const stored = await program.account.reactions.fetch(reactions);
+    expect(stored.counts).to.deep.equal([1, 1, 0, 0, 0, 0]);
+
+    await expectChatError(react(bob, 6), "InvalidReaction");
+  });
+
+  it("Assembles a 3 KB message from four chunks", async () => {
+    const payload = randomBytes(3072);
+    const chunkSize = payload.length / 4;
+    const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+    const parent = messagePda(messageCount);
+
+    await program.methods
+      .sendLargeMessage(new anchor.BN(0), bob.publicKey, 4, 0, Array.from(randomBytes(24)))
+      .accounts({
+        message: parent,
+        chatRoom: chatRoomPda,
+        inbox: inboxPda(bob.publicKey),
+        senderState: senderStatePda(chatRoomPda, alice.publicKey),
+        block: blockPda(bob.publicKey, alice.publicKey),
+        membership: null,
+        senderStats: statsPda(alice.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const appendChunk = (index: number) =>
+      program.methods
+        .appendChunk(index, Buffer.from(payload.subarray(index * chunkSize, (index + 1) * chunkSize)))
+        .accounts({
+          message: parent,
+          chunk: chunkPda(parent, index),
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+
+    // Chunks must arrive in order
+    await expectChatError(appendChunk(1), "UnexpectedChunkIndex");
+
+    for (let index = 0; index < 4; index++) {
+      await appendChunk(index);
+    }
+
+    const message = await program.account.message.fetch(parent);
+    expect(message.chunkCount).to.equal(4);
+    expect(message.chunksReceived).to.equal(4);
+
+    const chunks = await Promise.all(
+      [0, 1, 2, 3].map((index) => program.account.messageChunk.fetch(chunkPda(parent, index)))
+    );
+    const assembled = Buffer.concat(chunks.map((chunk) => Buffer.from(chunk.data)));
+    expect(assembled).to.deep.equal(payload);
+  });
+
+  it("Filters a recipient's messages by the published memcmp offset", async () => {
+    const constant = (name: string) =>
+      Number(program.idl.constants.find((c) => c.name === name).value);
+    const senderOffset = constant("SENDER_OFFSET");
+    const recipientOffset = constant("RECIPIENT_OFFSET");
+
+    const erin = Keypair.generate();
+    const { messagePda } = await sendMessage(alice, erin.publicKey, Buffer.from("for erin"));
+
+    const raw = await provider.connection.getAccountInfo(messagePda);
+    expect(raw.data.subarray(senderOffset, senderOffset + 32)).to.deep.equal(alice.publicKey.toBuffer());
+    expect(raw.data.subarray(recipientOffset, recipientOffset + 32)).to.deep.equal(erin.publicKey.toBuffer());
+
+    const erinsMessages = await program.account.message.all([
+      { memcmp: { offset: recipientOffset, bytes: erin.publicKey.toBase58() } },
+    ]);
+    expect(erinsMessages.map((m) => m.publicKey.toString())).to.deep.equal([messagePda.toString()]);
+  });
+
+  it("Enforces sent, delivered, read ordering", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("in order"));
+
+    await expectChatError(
+      program.methods
+        .acknowledgeMessage()
+        .accounts({
+          message: messagePda,
+          inbox: inboxPda(bob.publicKey),
+          recipientStats: statsPda(bob.publicKey),
+          settings: settingsPda(bob.publicKey),
+          readMarker: readMarkerPda(bob.publicKey, messagePda),
+          recipient: bob.publicKey,
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc(),
+      "NotDelivered"
+    );
+
+    await markDelivered(messagePda, bob);
+    let message = await program.account.message.fetch(messagePda);
+    expect(message.deliveredAt).to.not.be.null;
+    expect(message.readAt).to.be.null;
+
+    await expectChatError(markDelivered(messagePda, bob), "AlreadyDelivered");
+
+    await program.methods
+      .acknowledgeMessage()
+      .accounts({
+        message: messagePda,
+        inbox: inboxPda(bob.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        settings: settingsPda(bob.publicKey),
+        readMarker: readMarkerPda(bob.publicKey, messagePda),
+        recipient: bob.publicKey,
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+    message = await program.account.message.fetch(messagePda);
+    expect(message.readAt.toNumber()).to.be.at.least(message.deliveredAt.toNumber());
+  });
+
+  it("Unsends a message within the recall window", async () => {
+    const { messagePda, messageId } = await sendMessage(alice, bob.publicKey, Buffer.from("oops"));
+
+    let listener: number;
+    const recalled = new Promise<any>((resolve) => {
+      listener = program.addEventListener("messageRecalled", (e) => resolve(e));
+    });
+
+    await expectChatError(
+      program.methods
+        .unsendMessage()
+        .accounts({ message: messagePda, parent: null, sender: bob.publicKey })
+        .signers([bob])
+        .rpc(),
+      "NotSender"
+    );
+
+    await program.methods
+      .unsendMessage()
+      .accounts({ message: messagePda, parent: null, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    const event = await recalled;
+    await program.removeEventListener(listener);
+    expect(event.messageId.toNumber()).to.equal(messageId);
+    expect(await provider.connection.getAccountInfo(messagePda)).to.be.null;
+  });
+
+  it("Gates sending on membership in gated rooms", async () => {
+    const roomId = 12;
+    const room = await initializeRoom(roomId, alice, { gated: true });
+
+    await expectChatError(
+      sendMessage(bob, alice.publicKey, Buffer.from("knock knock"), { roomId }),
+      "NotAMember"
+    );
+
+    await program.methods
+      .addMember(bob.publicKey)
+      .accounts({
+        chatRoom: room,
+        membership: membershipPda(room, bob.publicKey),
+        authority: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+    expect((await program.account.chatRoom.fetch(room)).memberCount).to.equal(1);
+
+    await sendMessage(bob, alice.publicKey, Buffer.from("I'm in"), { roomId, asMember: true });
+
+    await program.methods
+      .removeMember()
+      .accounts({
+        chatRoom: room,
+        membership: membershipPda(room, bob.publicKey),
+        moderatorMembership: null,
+        moderator: alice.publicKey,
+      })
+      .signers([alice])
+      .rpc();
+    expect((await program.account.chatRoom.fetch(room)).memberCount).to.equal(0);
+
+    await expectChatError(
+      sendMessage(bob, alice.publicKey, Buffer.from("still here?"), { roomId }),
+      "NotAMember"
+    );
+  });
+
+  it("Tracks an unread badge count per inbox", async () => {
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+
+    await expectChatError(
+      program.methods
+        .markAllRead()
+        .accounts({ inbox: inboxPda(carol.publicKey), owner: carol.publicKey })
+        .signers([carol])
+        .rpc(),
+      "NoInbox"
+    );
+
+    const { messagePda } = await sendMessage(alice, carol.publicKey, Buffer.from("one"));
+    await sendMessage(alice, carol.publicKey, Buffer.from("two"));
+    await sendMessage(alice, carol.publicKey, Buffer.from("three"));
+    let inbox = await program.account.userInbox.fetch(inboxPda(carol.publicKey));
+    expect(inbox.unreadCount.toNumber()).to.equal(3);
+
+    await markDelivered(messagePda, carol);
+    await program.methods
+      .acknowledgeMessage()
+      .accounts({
+        message: messagePda,
+        inbox: inboxPda(carol.publicKey),
+        recipientStats: statsPda(carol.publicKey),
+        settings: settingsPda(carol.publicKey),
+        readMarker: readMarkerPda(carol.publicKey, messagePda),
+        recipient: carol.publicKey,
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([carol])
+      .rpc();
+    inbox = await program.account.userInbox.fetch(inboxPda(carol.publicKey));
+    expect(inbox.unreadCount.toNumber()).to.equal(2);
+
+    await program.methods
+      .markAllRead()
+      .accounts({ inbox: inboxPda(carol.publicKey), owner: carol.publicKey })
+      .signers([carol])
+      .rpc();
+    inbox = await program.account.userInbox.fetch(inboxPda(carol.publicKey));
+    expect(inbox.unreadCount.toNumber()).to.equal(0);
+    expect(inbox.lastReadAt).to.not.be.null;
+  });
+
+  it("Escrows a SOL tip until the recipient claims it", async () => {
+    const tip = 0.25 * anchor.web3.LAMPORTS_PER_SOL;
+    const sendWithTip = async (amount: number) => {
+      const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+      const message = messagePda(messageCount);
+      await program.methods
+        .sendMessageWithTip(
+          new anchor.BN(0),
+          Array.from(Buffer.from("coffee's on me")),
+          bob.publicKey,
+          0,
+          Array.from(randomBytes(24)),
+          null,
+          null,
+          Array.from(Buffer.alloc(64)),
+          null,
+          0,
+          new anchor.BN(amount)
+        )
+        .accounts({
+          send: sendAccounts(alice.publicKey, bob.publicKey, chatRoomPda, message),
+          tipEscrow: tipEscrowPda(message),
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      return message;
+    };
+
+    await expectChatError(sendWithTip(0), "ZeroTip");
+
+    const message = await sendWithTip(tip);
+    const escrow = tipEscrowPda(message);
+    expect((await program.account.tipEscrow.fetch(escrow)).amountLamports.toNumber()).to.equal(tip);
+
+    await expectChatError(
+      program.methods
+        .claimTip()
+        .accounts({ tipEscrow: escrow, recipient: alice.publicKey })
+        .signers([alice])
+        .rpc(),
+      "NotRecipient"
+    );
+
+    const escrowBalance = await provider.connection.getBalance(escrow);
+    const bobBefore = await provider.connection.getBalance(bob.publicKey);
+    await program.methods
+      .claimTip()
+      .accounts({ tipEscrow: escrow, recipient: bob.publicKey })
+      .signers([bob])
+      .rpc();
+
+    expect(await provider.connection.getAccountInfo(escrow)).to.be.null;
+    // Bob pays the fee, so expect the escrowed amount less at most one fee
+    const bobAfter = await provider.connection.getBalance(bob.publicKey);
+    expect(bobAfter).to.be.greaterThan(bobBefore + tip - 10_000);
+    expect(bobAfter).to.be.at.most(bobBefore + escrowBalance);
+  });
+
+  it("Escrows an SPL token tip and pays it to the recipient's ATA", async () => {
+    const mint = await createMint(provider.connection, alice, alice.publicKey, null, 6);
+    const otherMint = await createMint(provider.connection, alice, alice.publicKey, null, 6);
+    const aliceToken = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      alice,
+      mint,
+      alice.publicKey
+    );
+    await mintTo(provider.connection, alice, mint, aliceToken.address, alice, 1_000_000);
+
+    const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+    const message = messagePda(messageCount);
+    const { tokenTipEscrow, vault } = tokenTipPdas(message);
+    await program.methods
+      .sendMessageWithTokenTip(
+        new anchor.BN(0),
+        Array.from(Buffer.from("have some tokens")),
+        bob.publicKey,
+        0,
+        Array.from(randomBytes(24)),
+        null,
+        null,
+        Array.from(Buffer.alloc(64)),
+        null,
+        0,
+        new anchor.BN(250_000)
+      )
+      .accounts({
+        send: sendAccounts(alice.publicKey, bob.publicKey, chatRoomPda, message),
+        tokenTipEscrow,
+        vault,
+        mint,
+        senderToken: aliceToken.address,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+    expect(Number((await getAccount(provider.connection, vault)).amount)).to.equal(250_000);
+
+    const claim = (claimMint: PublicKey) =>
+      program.methods
+        .claimTokenTip()
+        .accounts({
+          tokenTipEscrow,
+          vault,
+          mint: claimMint,
+          recipientToken: getAssociatedTokenAddressSync(claimMint, bob.publicKey),
+          recipient: bob.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    await expectChatError(claim(otherMint), "MintMismatch");
+    await claim(mint);
+
+    const bobToken = await getAccount(
+      provider.connection,
+      getAssociatedTokenAddressSync(mint, bob.publicKey)
+    );
+    expect(Number(bobToken.amount)).to.equal(250_000);
+    expect(await provider.connection.getAccountInfo(vault)).to.be.null;
+    expect(await provider.connection.getAccountInfo(tokenTipEscrow)).to.be.null;
+  });
+
+  it("Verifies a detached content-hash signature against the registered key", async () => {
+    const dave = Keypair.generate();
+    await airdrop(dave.publicKey);
+    const signingKey = nacl.sign.keyPair();
+    await program.methods
+      .registerKey(Array.from(signingKey.publicKey))
+      .accounts({
+        keyRegistry: keyRegistryPda(dave.publicKey),
+        owner: dave.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([dave])
+      .rpc();
+
+    const content = randomBytes(64);
+    const contentHash = Buffer.from(keccak_256(content));
+    const signature = Buffer.from(nacl.sign.detached(contentHash, signingKey.secretKey));
+    const sendSigned = async (claimed: Buffer, withVerifyIx = true) => {
+      const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+      const message = messagePda(messageCount);
+      await program.methods
+        .sendMessage(
+          new anchor.BN(0),
+          Array.from(content),
+          bob.publicKey,
+          0,
+          Array.from(randomBytes(24)),
+          null,
+          null,
+          Array.from(claimed),
+          null,
+          0,
+          null,
+          false,
+          null,
+          false
+        )
+        .accounts({
+          ...sendAccounts(dave.publicKey, bob.publicKey, chatRoomPda, message),
+          keyRegistry: keyRegistryPda(dave.publicKey),
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .preInstructions(
+          withVerifyIx
+            ? [
+                anchor.web3.Ed25519Program.createInstructionWithPublicKey({
+                  publicKey: signingKey.publicKey,
+                  message: contentHash,
+                  signature,
+                }),
+              ]
+            : []
+        )
+        .signers([dave])
+        .rpc();
+      return message;
+    };
+
+    // The ed25519 instruction is valid, but the stored signature was tampered with
+    const tampered = Buffer.from(signature);
+    tampered[0] ^= 0xff;
+    await expectChatError(sendSigned(tampered), "SignatureVerificationFailed");
+    // A correct signature is not enough without the ed25519 instruction
+    await expectChatError(sendSigned(signature, false), "SignatureVerificationFailed");
+
+    const message = await sendSigned(signature);
+    const stored = await program.account.message.fetch(message);
+    expect(Buffer.from(stored.contentSignature)).to.deep.equal(signature);
+    expect(Buffer.from(stored.contentHash)).to.deep.equal(contentHash);
+    expect(stored.signatureVerified).to.be.true;
+  });
+
+  it("Toggles a typing indicator without recreating its account", async () => {
+    const conversation = conversationPda(alice.publicKey, bob.publicKey);
+    const typingState = PublicKey.findProgramAddressSync(
+      [Buffer.from("typing"), conversation.toBuffer(), bob.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const setTyping = (user: Keypair, isTyping: boolean, state = typingState) =>
+      program.methods
+        .setTyping(isTyping)
+        .accounts({
+          conversation,
+          typingState: state,
+          user: user.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+    await setTyping(bob, true);
+    let state = await program.account.typingState.fetch(typingState);
+    expect(state.isTyping).to.be.true;
+    const lamports = (await provider.connection.getAccountInfo(typingState)).lamports;
+
+    await setTyping(bob, false);
+    state = await program.account.typingState.fetch(typingState);
+    expect(state.isTyping).to.be.false;
+    expect((await provider.connection.getAccountInfo(typingState)).lamports).to.equal(lamports);
+
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+    const carolState = PublicKey.findProgramAddressSync(
+      [Buffer.from("typing"), conversation.toBuffer(), carol.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    await expectChatError(setTyping(carol, true, carolState), "NotAParticipant");
+  });
+
+  it("Pins up to five messages per room", async () => {
+    const roomId = 13;
+    const room = await initializeRoom(roomId);
+    for (let i = 0; i < 6; i++) {
+      await sendMessage(alice, bob.publicKey, Buffer.from(`announcement ${i}`), { roomId });
+    }
+    const pinned = PublicKey.findProgramAddressSync(
+      [Buffer.from("pinned"), room.toBuffer()],
+      program.programId
+    )[0];
+    const pin = (messageId: number, authority = alice) =>
+      program.methods
+        .pinMessage(new anchor.BN(messageId))
+        .accounts({
+          chatRoom: room,
+          pinned,
+          moderatorMembership: null,
+          moderator: authority.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([authority])
+        .rpc();
+
+    await expectChatError(pin(0, bob), "Unauthorized");
+    await expectChatError(pin(99), "MessageNotFound");
+    for (let i = 0; i < 5; i++) {
+      await pin(i);
+    }
+    await expectChatError(pin(0), "PinLimitReached");
+    await expectChatError(pin(5), "PinLimitReached");
+
+    await program.methods
+      .unpinMessage(new anchor.BN(2))
+      .accounts({ chatRoom: room, pinned, moderatorMembership: null, moderator: alice.publicKey })
+      .signers([alice])
+      .rpc();
+    await pin(5);
+
+    const { messageIds } = await program.account.pinnedMessages.fetch(pinned);
+    expect(messageIds.map((id) => id.toNumber())).to.deep.equal([0, 1, 3, 4, 5]);
+  });
+
+  it("Batch sends a distinct ciphertext to each recipient", async () => {
+    const recipients = [bob.publicKey, Keypair.generate().publicKey, Keypair.generate().publicKey];
+    const ciphertexts = recipients.map((_, i) => Buffer.from(`for recipient ${i}`));
+    const batchSend = async (to: PublicKey[], contents: Buffer[]) => {
+      const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+      const messages = to.map((_, i) => messagePda(messageCount.addn(i)));
+      await program.methods
+        .batchSend(
+          new anchor.BN(0),
+          to,
+          contents.map((content) => Buffer.from(content)),
+          0,
+          to.map(() => Array.from(randomBytes(24)))
+        )
+        .accounts({
+          chatRoom: chatRoomPda,
+          senderState: senderStatePda(chatRoomPda, alice.publicKey),
+          membership: null,
+          senderStats: statsPda(alice.publicKey),
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          to.flatMap((recipient, i) =>
+            [
+              messages[i],
+              inboxPda(recipient),
+              blockPda(recipient, alice.publicKey),
+              statsPda(recipient),
+            ].map((pubkey) => ({ pubkey, isSigner: false, isWritable: true }))
+          )
+        )
+        .signers([alice])
+        .rpc();
+      return messages;
+    };
+
+    await expectChatError(batchSend([], []), "BatchTooLarge");
+    await expectChatError(batchSend(recipients, ciphertexts.slice(1)), "BatchLengthMismatch");
+
+    const before = (await program.account.chatRoom.fetch(chatRoomPda)).messageCount.toNumber();
+    const messages = await batchSend(recipients, ciphertexts);
+    const after = (await program.account.chatRoom.fetch(chatRoomPda)).messageCount.toNumber();
+    expect(after).to.equal(before + recipients.length);
+
+    for (let i = 0; i < recipients.length; i++) {
+      const message = await program.account.message.fetch(messages[i]);
+      expect(message.recipient.toString()).to.equal(recipients[i].toString());
+      expect(message.messageId.toNumber()).to.equal(before + i);
+      expect(Buffer.from(message.encryptedContent)).to.deep.equal(ciphertexts[i]);
+      const inbox = await program.account.userInbox.fetch(inboxPda(recipients[i]));
+      expect(inbox.recentMessages.some((entry) => entry.messageId.toNumber() === before + i)).to
+        .be.true;
+    }
+  });
+
+  it("Records one report per user and rejects self-reports", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("buy my coin"));
+    const report = (reporter: Keypair, reasonCode: number) =>
+      program.methods
+        .reportMessage(reasonCode)
+        .accounts({
+          message: messagePda,
+          report: PublicKey.findProgramAddressSync(
+            [Buffer.from("report"), messagePda.toBuffer(), reporter.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          reporter: reporter.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([reporter])
+        .rpc();
+
+    await expectChatError(report(alice, 0), "CannotReportOwn");
+    await expectChatError(report(bob, 4), "InvalidReasonCode");
+
+    await report(bob, 0);
+    const message = await program.account.message.fetch(messagePda);
+    expect(message.reportCount).to.equal(1);
+
+    // The Report PDA already exists, so a second report fails to initialize it
+    let failed = false;
+    try {
+      await report(bob, 1);
+    } catch {
+      failed = true;
+    }
+    expect(failed).to.be.true;
+  });
+
+  it("Registers unique usernames and frees them on release", async () => {
+    const toName = (name: string) => {
+      const padded = Buffer.alloc(32);
+      padded.write(name);
+      return padded;
+    };
+    const usernamePda = (name: Buffer) =>
+      PublicKey.findProgramAddressSync([Buffer.from("username"), name], program.programId)[0];
+    const profilePda = (owner: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("profile"), owner.toBuffer()],
+        program.programId
+      )[0];
+    const register = (owner: Keypair, name: Buffer) =>
+      program.methods
+        .registerUsername(Array.from(name))
+        .accounts({
+          username: usernamePda(name),
+          profile: profilePda(owner.publicKey),
+          owner: owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+
+    const name = toName("alice");
+    await expectChatError(register(alice, toName("Alice!")), "InvalidUsername");
+    await register(alice, name);
+    const username = await program.account.username.fetch(usernamePda(name));
+    expect(username.owner.toString()).to.equal(alice.publicKey.toString());
+
+    await expectChatError(register(bob, name), "UsernameTaken");
+    await expectChatError(register(alice, toName("alice2")), "AlreadyHasUsername");
+
+    await program.methods
+      .releaseUsername()
+      .accounts({
+        username: usernamePda(name),
+        profile: profilePda(alice.publicKey),
+        owner: alice.publicKey,
+      })
+      .signers([alice])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(usernamePda(name))).to.be.null;
+
+    await register(bob, name);
+    const profile = await program.account.userProfile.fetch(profilePda(bob.publicKey));
+    expect(Buffer.from(profile.name)).to.deep.equal(name);
+  });
+
+  it("Maintains a bounded contacts list", async () => {
+    const owner = Keypair.generate();
+    await airdrop(owner.publicKey);
+    const contacts = PublicKey.findProgramAddressSync(
+      [Buffer.from("contacts"), owner.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const addContact = (contact: PublicKey) =>
+      program.methods
+        .addContact(contact)
+        .accounts({
+          contacts,
+          owner: owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+    const removeContact = (contact: PublicKey) =>
+      program.methods
+        .removeContact(contact)
+        .accounts({ contacts, owner: owner.publicKey })
+        .signers([owner])
+        .rpc();
+
+    await addContact(bob.publicKey);
+    await expectChatError(addContact(bob.publicKey), "ContactExists");
+
+    await removeContact(bob.publicKey);
+    expect((await program.account.contacts.fetch(contacts)).contacts).to.be.empty;
+    await expectChatError(removeContact(bob.publicKey), "ContactNotFound");
+
+    for (let i = 0; i < 32; i++) {
+      await addContact(Keypair.generate().publicKey);
+    }
+    await expectChatError(addContact(bob.publicKey), "ContactLimitReached");
+    expect((await program.account.contacts.fetch(contacts)).contacts).to.have.length(32);
+  });
+
+  it("Anchors an off-chain attachment CID on a message", async () => {
+    const cid = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("see attached"), {
+      attachment: { kind: 1, cid },
+    });
+    const message = await program.account.message.fetch(messagePda);
+    expect(message.attachmentKind).to.equal(1);
+    expect(message.attachmentCid).to.equal(cid);
+
+    const plain = await sendMessage(alice, bob.publicKey, Buffer.from("no attachment"));
+    expect((await program.account.message.fetch(plain.messagePda)).attachmentKind).to.equal(0);
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("oops"), { attachment: { kind: 2, cid: "" } }),
+      "MissingAttachment"
+    );
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("oops"), { attachment: { kind: 9, cid } }),
+      "UnknownAttachmentKind"
+    );
+  });
+
+  it("Stores the message priority and emits it with MessageSent", async () => {
+    for (const priority of [0, 1, 2]) {
+      const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("alert"), {
+        priority,
+      });
+      expect((await program.account.message.fetch(messagePda)).priority).to.equal(priority);
+    }
+
+    let listener: number;
+    const event = new Promise<any>((resolve) => {
+      listener = program.addEventListener("messageSent", (e) => resolve(e));
+    });
+    await sendMessage(alice, bob.publicKey, Buffer.from("security alert"), { priority: 2 });
+    const sent = await event;
+    await program.removeEventListener(listener);
+    expect(sent.priority).to.equal(2);
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("???"), { priority: 3 }),
+      "InvalidPriority"
+    );
+  });
+
+  it("Returns a message summary through simulation", async () => {
+    const content = Buffer.from("view me");
+    const { messagePda, messageId } = await sendMessage(alice, bob.publicKey, content);
+
+    const view = await program.methods
+      .getMessage(new anchor.BN(messageId))
+      .accounts({ message: messagePda })
+      .view();
+    expect(view.sender.toString()).to.equal(alice.publicKey.toString());
+    expect(view.recipient.toString()).to.equal(bob.publicKey.toString());
+    expect(view.messageId.toNumber()).to.equal(messageId);
+    expect(view.contentLen).to.equal(content.length);
+
+    // A mismatched id fails the PDA check
+    let failed = false;
+    try {
+      await program.methods
+        .getMessage(new anchor.BN(messageId + 1))
+        .accounts({ message: messagePda })
+        .view();
+    } catch {
+      failed = true;
+    }
+    expect(failed).to.be.true;
+  });
+
+  it("Lists a user's recent messages from their inbox", async () => {
+    const carol = Keypair.generate();
+    const list = (user: PublicKey) =>
+      program.methods.getMessagesForUser(user).accounts({ inbox: inboxPda(user) }).view();
+
+    expect(await list(carol.publicKey)).to.be.empty;
+
+    const first = await sendMessage(alice, carol.publicKey, Buffer.from("hi carol"));
+    const second = await sendMessage(alice, carol.publicKey, Buffer.from("again"));
+    const entries = await list(carol.publicKey);
+    expect(entries.map((entry) => entry.messageId.toNumber())).to.deep.equal([
+      first.messageId,
+      second.messageId,
+    ]);
+    expect(entries[0].room.toString()).to.equal(chatRoomPda.toString());
+  });
+
+  it("Tracks the latest message on the room for previews", async () => {
+    const { messagePda, messageId } = await sendMessage(alice, bob.publicKey, Buffer.from("latest"));
+    const room = await program.account.chatRoom.fetch(chatRoomPda);
+    const message = await program.account.message.fetch(messagePda);
+
+    expect(room.lastMessageId.toNumber()).to.equal(room.messageCount.toNumber() - 1);
+    expect(room.lastMessageId.toNumber()).to.equal(messageId);
+    expect(room.lastActivity.toNumber()).to.equal(message.timestamp.toNumber());
+  });
+
+  it("Archives and mutes a conversation independently", async () => {
+    const conversation = conversationPda(alice.publicKey, bob.publicKey);
+    const conversationState = PublicKey.findProgramAddressSync(
+      [Buffer.from("conv_state"), bob.publicKey.toBuffer(), conversation.toBuffer()],
+      program.programId
+    )[0];
+    const accounts = {
+      conversation,
+      conversationState,
+      user: bob.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+    const fetchState = () => program.account.conversationState.fetch(conversationState);
+
+    await program.methods.setArchived(true).accounts(accounts).signers([bob]).rpc();
+    let state = await fetchState();
+    expect(state.archived).to.be.true;
+    expect(state.muted).to.be.false;
+
+    await program.methods.setMuted(true).accounts(accounts).signers([bob]).rpc();
+    await program.methods.setArchived(false).accounts(accounts).signers([bob]).rpc();
+    state = await fetchState();
+    expect(state.archived).to.be.false;
+    expect(state.muted).to.be.true;
+  });
+
+  it("Records a hash of the prior ciphertext on each edit", async () => {
+    const versions = ["v0", "v1", "v2", "v3"].map((v) => Buffer.from(v));
+    const { messagePda } = await sendMessage(alice, bob.publicKey, versions[0]);
+    for (const next of versions.slice(1)) {
+      await program.methods
+        .editMessage(Array.from(next))
+        .accounts({ message: messagePda, room: chatRoomPda, sender: alice.publicKey })
+        .signers([alice])
+        .rpc();
+    }
+
+    const message = await program.account.message.fetch(messagePda);
+    expect(message.editCount).to.equal(3);
+    expect(message.prevContentHashes.map((hash) => Buffer.from(hash))).to.deep.equal(
+      versions.slice(0, 3).map((v) => Buffer.from(keccak_256(v)))
+    );
+  });
+
+  it("Closes an inbox and refunds its rent to the owner", async () => {
+    const erin = Keypair.generate();
+    await airdrop(erin.publicKey);
+    const inbox = inboxPda(erin.publicKey);
+    await program.methods
+      .initInbox()
+      .accounts({
+        inbox,
+        owner: erin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([erin])
+      .rpc();
+
+    const rent = await provider.connection.getBalance(inbox);
+    const before = await provider.connection.getBalance(erin.publicKey);
+    await program.methods
+      .closeInbox()
+      .accounts({ inbox, owner: erin.publicKey })
+      .signers([erin])
+      .rpc();
+
+    expect(await provider.connection.getAccountInfo(inbox)).to.be.null;
+    // Erin pays the transaction fee out of the refund
+    const after = await provider.connection.getBalance(erin.publicKey);
+    expect(after).to.be.greaterThan(before + rent - 10_000);
+  });
+
+  it("Numbers each recipient's messages contiguously", async () => {
+    const frank = Keypair.generate().publicKey;
+    const seqs = [];
+    for (const content of ["a", "b", "c"]) {
+      const { messagePda } = await sendMessage(alice, frank, Buffer.from(content));
+      seqs.push((await program.account.message.fetch(messagePda)).recipientSeq.toNumber());
+    }
+    expect(seqs).to.deep.equal([0, 1, 2]);
+  });
+
+  it("Mutes a sender without blocking their messages", async () => {
+    const mute = PublicKey.findProgramAddressSync(
+      [Buffer.from("mute"), bob.publicKey.toBuffer(), alice.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const muteAlice = () =>
+      program.methods
+        .muteUser(alice.publicKey)
+        .accounts({
+          mute,
+          muter: bob.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    await muteAlice();
+    await expectChatError(muteAlice(), "AlreadyMuted");
+
+    // Muting is a client hint; the muted sender's messages still arrive
+    await sendMessage(alice, bob.publicKey, Buffer.from("you can't hear me"));
+
+    await program.methods
+      .unmuteUser()
+      .accounts({ mute, muter: bob.publicKey })
+      .signers([bob])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(mute)).to.be.null;
+  });
+
+  it("Broadcasts one message to a whole group", async () => {
+    const roomId = 14;
+    const room = await initializeRoom(roomId, alice, { gated: true });
+    await addMember(room, alice.publicKey);
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+
+    const broadcast = async (sender: Keypair, asMember: boolean) => {
+      const { messageCount } = await program.account.chatRoom.fetch(room);
+      const message = messagePda(messageCount, room);
+      await program.methods
+        .broadcastToGroup(
+          new anchor.BN(roomId),
+          Buffer.from("meeting at noon"),
+          0,
+          Array.from(randomBytes(24))
+        )
+        .accounts({
+          message,
+          chatRoom: room,
+          senderState: senderStatePda(room, sender.publicKey),
+          membership: asMember ? membershipPda(room, sender.publicKey) : null,
+          senderStats: statsPda(sender.publicKey),
+          sender: sender.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([sender])
+        .rpc();
+      return message;
+    };
+
+    await expectChatError(broadcast(carol, false), "NotAMember");
+
+    const message = await program.account.message.fetch(await broadcast(alice, true));
+    expect(message.recipient.toString()).to.equal(room.toString());
+    expect(message.groupId.toNumber()).to.equal(roomId);
+  });
+
+  it("Heartbeats presence through each status", async () => {
+    const presence = PublicKey.findProgramAddressSync(
+      [Buffer.from("presence"), bob.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const setPresence = (status: number) =>
+      program.methods
+        .setPresence(status)
+        .accounts({
+          presence,
+          user: bob.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    for (const status of [1, 2, 0]) {
+      await setPresence(status);
+      const stored = await program.account.presence.fetch(presence);
+      expect(stored.status).to.equal(status);
+      // Fresh heartbeat: clients show the stored status until it is 60s old
+      expect((await chainTime()) - stored.lastSeen.toNumber()).to.be.at.most(60);
+    }
+
+    await expectChatError(setPresence(3), "InvalidPresenceStatus");
+  });
+
+  it("Admits members to a gated room by invite code", async () => {
+    const roomId = 15;
+    const room = await initializeRoom(roomId, alice, { gated: true });
+    const code = Buffer.from("open-sesame");
+    const codeHash = Buffer.from(keccak_256(code));
+    const invite = PublicKey.findProgramAddressSync(
+      [Buffer.from("invite"), room.toBuffer(), codeHash],
+      program.programId
+    )[0];
+
+    await program.methods
+      .createInvite(Array.from(codeHash), 1)
+      .accounts({
+        chatRoom: room,
+        invite,
+        authority: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const redeem = (redeemer: Keypair, attempt: Buffer) =>
+      program.methods
+        .redeemInvite(attempt)
+        .accounts({
+          chatRoom: room,
+          invite,
+          membership: membershipPda(room, redeemer.publicKey),
+          redeemer: redeemer.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([redeemer])
+        .rpc();
+
+    await expectChatError(redeem(bob, Buffer.from("open-barley")), "InvalidInviteCode");
+
+    await redeem(bob, code);
+    await sendMessage(bob, alice.publicKey, Buffer.from("thanks for the invite"), {
+      roomId,
+      asMember: true,
+    });
+
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+    await expectChatError(redeem(carol, code), "InviteExhausted");
+    expect((await program.account.chatRoom.fetch(room)).memberCount).to.equal(1);
+  });
+
+  it("Lets moderators pin and only admins change roles", async () => {
+    const roomId = 16;
+    const room = await initializeRoom(roomId);
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+    await addMember(room, bob.publicKey);
+    await addMember(room, carol.publicKey);
+    await sendMessage(alice, bob.publicKey, Buffer.from("pin me"), { roomId });
+
+    const pinned = PublicKey.findProgramAddressSync(
+      [Buffer.from("pinned"), room.toBuffer()],
+      program.programId
+    )[0];
+    const pin = (moderator: Keypair) =>
+      program.methods
+        .pinMessage(new anchor.BN(0))
+        .accounts({
+          chatRoom: room,
+          pinned,
+          moderatorMembership: membershipPda(room, moderator.publicKey),
+          moderator: moderator.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([moderator])
+        .rpc();
+    const setRole = (member: PublicKey, role: number, admin: Keypair = alice) =>
+      program.methods
+        .setRole(member, role)
+        .accounts({
+          chatRoom: room,
+          membership: membershipPda(room, member),
+          adminMembership: admin === alice ? null : membershipPda(room, admin.publicKey),
+          admin: admin.publicKey,
+        })
+        .signers([admin])
+        .rpc();
+
+    // Plain members can do neither
+    await expectChatError(pin(bob), "Unauthorized");
+    await expectChatError(setRole(carol.publicKey, 1, bob), "Unauthorized");
+
+    // A moderator can pin but not hand out roles
+    await setRole(bob.publicKey, 1);
+    await pin(bob);
+    await expectChatError(setRole(carol.publicKey, 1, bob), "Unauthorized");
+    await expectChatError(setRole(carol.publicKey, 3), "InvalidRole");
+
+    // An admin can do both
+    await setRole(carol.publicKey, 2);
+    await program.methods
+      .unpinMessage(new anchor.BN(0))
+      .accounts({
+        chatRoom: room,
+        pinned,
+        moderatorMembership: membershipPda(room, carol.publicKey),
+        moderator: carol.publicKey,
+      })
+      .signers([carol])
+      .rpc();
+    await pin(carol);
+    await setRole(bob.publicKey, 0, carol);
+    expect((await program.account.membership.fetch(membershipPda(room, bob.publicKey))).role).to.equal(0);
+
+    // ...but cannot step down while they are the only admin
+    await expectChatError(setRole(carol.publicKey, 1, carol), "LastAdmin");
+    expect((await program.account.chatRoom.fetch(room)).adminCount).to.equal(1);
+  });
+
+  it("Mirrors the reaction total onto the message", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("tally me"));
+    const reactions = reactionsPda(messagePda);
+    const react = (reactor: Keypair, index: number) =>
+      program.methods
+        .addReaction(index)
+        .accounts({
+          message: messagePda,
+          reactions,
+          reactor: reactor.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([reactor])
+        .rpc();
+    const unreact = (index: number) =>
+      program.methods
+        .removeReaction(index)
+        .accounts({ message: messagePda, reactions, reactor: bob.publicKey })
+        .signers([bob])
+        .rpc();
+
+    await react(bob, 0);
+    await react(alice, 2);
+    await unreact(2);
+    // Removing from an empty slot must not pull the total down
+    await unreact(3);
+
+    const { counts } = await program.account.reactions.fetch(reactions);
+    expect(counts.reduce((sum, count) => sum + count, 0)).to.equal(1);
+    expect((await program.account.message.fetch(messagePda)).totalReactions).to.equal(1);
+  });
+
+  it("Forwards a received message with its provenance", async () => {
+    const carol = Keypair.generate();
+    const { messagePda: original, messageId } = await sendMessage(
+      alice,
+      bob.publicKey,
+      Buffer.from("pass it on"),
+      { attachment: { kind: 1, cid: "bafyforwarded" } }
+    );
+    const forward = async (forwarder: Keypair, newRecipient: PublicKey) => {
+      const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+      const message = messagePda(messageCount);
+      await program.methods
+        .forwardMessage(newRecipient)
+        .accounts({
+          original,
+          message,
+          chatRoom: chatRoomPda,
+          inbox: inboxPda(newRecipient),
+          senderState: senderStatePda(chatRoomPda, forwarder.publicKey),
+          block: blockPda(newRecipient, forwarder.publicKey),
+          membership: null,
+          senderStats: statsPda(forwarder.publicKey),
+          recipientStats: statsPda(newRecipient),
+          forwarder: forwarder.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([forwarder])
+        .rpc();
+      return message;
+    };
+
+    // Only the recipient may forward
+    await expectChatError(forward(alice, carol.publicKey), "CannotForward");
+
+    const source = await program.account.message.fetch(original);
+    const forwarded = await program.account.message.fetch(await forward(bob, carol.publicKey));
+    expect(forwarded.sender.toString()).to.equal(bob.publicKey.toString());
+    expect(forwarded.recipient.toString()).to.equal(carol.publicKey.toString());
+    expect(Buffer.from(forwarded.encryptedContent)).to.deep.equal(Buffer.from("pass it on"));
+    expect(forwarded.nonce).to.deep.equal(source.nonce);
+    expect(forwarded.attachmentCid).to.equal("bafyforwarded");
+    expect(forwarded.forwardedFrom.toNumber()).to.equal(messageId);
+    expect(source.forwardedFrom).to.be.null;
+  });
+
+  it("Enforces a room's configured message length", async () => {
+    const roomId = 17;
+    await initializeRoom(roomId, alice, { maxMessageLen: 100 });
+
+    await sendMessage(alice, bob.publicKey, Buffer.alloc(99, 1), { roomId });
+    await sendMessage(alice, bob.publicKey, Buffer.alloc(100, 1), { roomId });
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.alloc(101, 1), { roomId }),
+      "MessageTooLong"
+    );
+
+    // Edits are held to the same cap, so a short message cannot grow past it
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.alloc(10, 1), { roomId });
+    const edit = (length: number) =>
+      program.methods
+        .editMessage(Array.from(Buffer.alloc(length, 2)))
+        .accounts({ message: messagePda, room: roomPda(roomId), sender: alice.publicKey })
+        .signers([alice])
+        .rpc();
+    await expectChatError(edit(101), "MessageTooLong");
+    await edit(100);
+
+    // The cap can only tighten the compiled 512-byte limit
+    await expectChatError(initializeRoom(18, alice, { maxMessageLen: 513 }), "ConfigTooLarge");
+  });
+
+  it("Stamps layout versions and guards migrate_message", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("versioned"));
+    expect((await program.account.message.fetch(messagePda)).version).to.equal(13);
+    expect((await program.account.chatRoom.fetch(chatRoomPda)).version).to.equal(8);
+
+    const migrate = (migrator: Keypair) =>
+      program.methods
+        .migrateMessage()
+        .accounts({
+          message: messagePda,
+          chatRoom: null,
+          migrator: migrator.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([migrator])
+        .rpc();
+
+    await expectChatError(migrate(bob), "Unauthorized");
+
+    // A current-version message migrates to itself
+    const before = (await provider.connection.getAccountInfo(messagePda)).data;
+    await migrate(alice);
+    expect((await provider.connection.getAccountInfo(messagePda)).data).to.deep.equal(before);
+  });
+
+  it("Reaps only the expired messages in a batch", async () => {
+    const carol = Keypair.generate();
+    const now = await chainTime();
+    const expiring = [
+      await sendMessage(alice, carol.publicKey, Buffer.from("gone soon"), { expiresAt: now + 2 }),
+      await sendMessage(bob, carol.publicKey, Buffer.from("gone soon"), { expiresAt: now + 2 }),
+    ];
+    const live = await sendMessage(alice, carol.publicKey, Buffer.from("still here"), {
+      expiresAt: now + 3600,
+    });
+    const permanent = await sendMessage(bob, carol.publicKey, Buffer.from("no expiry"));
+
+    while ((await chainTime()) < now + 2) {
+      await new Promise((resolve) => setTimeout(resolve, 500));
+    }
+
+    const batch = [
+      { pubkey: expiring[0].messagePda, sender: alice.publicKey },
+      { pubkey: live.messagePda, sender: alice.publicKey },
+      { pubkey: expiring[1].messagePda, sender: bob.publicKey },
+      { pubkey: permanent.messagePda, sender: bob.publicKey },
+    ];
+    const remainingAccounts = batch.flatMap(({ pubkey, sender }) => [
+      { pubkey, isWritable: true, isSigner: false },
+      { pubkey: sender, isWritable: true, isSigner: false },
+    ]);
+    let rent = 0;
+    for (const { messagePda } of expiring) {
+      rent += (await provider.connection.getAccountInfo(messagePda)).lamports;
+    }
+    const reaped = await program.methods
+      .reapExpiredBatch()
+      .accounts({})
+      .remainingAccounts(remainingAccounts)
+      .view();
+    expect(reaped.closedCount).to.equal(2);
+    expect(reaped.lamportsReclaimed.toNumber()).to.equal(rent);
+
+    const bobBefore = await provider.connection.getBalance(bob.publicKey);
+    await program.methods
+      .reapExpiredBatch()
+      .accounts({})
+      .remainingAccounts(remainingAccounts)
+      .rpc();
+
+    for (const { messagePda } of expiring) {
+      expect(await provider.connection.getAccountInfo(messagePda)).to.be.null;
+    }
+    await program.account.message.fetch(live.messagePda);
+    await program.account.message.fetch(permanent.messagePda);
+    expect(await provider.connection.getBalance(bob.publicKey)).to.be.greaterThan(bobBefore);
+  });
+
+  it("Stores the keccak hash of the ciphertext", async () => {
+    const payload = Buffer.from("integrity matters");
+    const { messagePda } = await sendMessage(alice, bob.publicKey, payload);
+
+    const { contentHash, encryptedContent } = await program.account.message.fetch(messagePda);
+    expect(Buffer.from(contentHash)).to.deep.equal(Buffer.from(keccak_256(payload)));
+    expect(Buffer.from(keccak_256(Buffer.from(encryptedContent)))).to.deep.equal(Buffer.from(contentHash));
+  });
+
+  it("Cancels a proposed authority transfer", async () => {
+    const room = await initializeRoom(19);
+    await proposeAuthority(room, bob.publicKey);
+    await program.methods
+      .cancelAuthorityTransfer()
+      .accounts({ chatRoom: room, authority: alice.publicKey })
+      .signers([alice])
+      .rpc();
+
+    const stored = await program.account.chatRoom.fetch(room);
+    expect(stored.pendingAuthority).to.be.null;
+    await expectChatError(acceptAuthority(room, bob), "NotPendingAuthority");
+    expect(stored.authority.toString()).to.equal(alice.publicKey.toString());
+  });
+
+  it("Rejects accept_authority from anyone but the pending authority", async () => {
+    const room = await initializeRoom(20);
+    const mallory = Keypair.generate();
+    await airdrop(mallory.publicKey);
+    await proposeAuthority(room, bob.publicKey);
+
+    await expectChatError(acceptAuthority(room, mallory), "NotPendingAuthority");
+    await expectChatError(acceptAuthority(room, alice), "NotPendingAuthority");
+    const stored = await program.account.chatRoom.fetch(room);
+    expect(stored.authority.toString()).to.equal(alice.publicKey.toString());
+    expect(stored.pendingAuthority.toString()).to.equal(bob.publicKey.toString());
+  });
+
+  it("Stamps the room's TTL on every new message", async () => {
+    const roomId = 21;
+    const room = await initializeRoom(roomId, alice, { defaultTtlSecs: 60 });
+    const now = await chainTime();
+
+    // The room policy wins over the sender's own choice
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("poof"), {
+      roomId,
+      expiresAt: now + 3600,
+    });
+    const stamped = await program.account.message.fetch(messagePda);
+    expect(stamped.expiresAt.toNumber()).to.equal(stamped.timestamp.toNumber() + 60);
+
+    const setRoomTtl = (ttl: number | null, authority: Keypair = alice) =>
+      program.methods
+        .setRoomTtl(ttl)
+        .accounts({ chatRoom: room, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+    await expectChatError(setRoomTtl(null, bob), "Unauthorized");
+    await setRoomTtl(null);
+
+    const { messagePda: kept } = await sendMessage(alice, bob.publicKey, Buffer.from("stays"), {
+      roomId,
+    });
+    expect((await program.account.message.fetch(kept)).expiresAt).to.be.null;
+  });
+
+  it("Reassigns an undelivered message to a new recipient", async () => {
+    const dave = Keypair.generate();
+    const erin = Keypair.generate();
+    await airdrop(erin.publicKey);
+    await program.methods
+      .initStats()
+      .accounts({
+        stats: statsPda(erin.publicKey),
+        user: erin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([erin])
+      .rpc();
+    const { messagePda, messageId } = await sendMessage(alice, dave.publicKey, Buffer.from("oops"));
+    const reassign = async (newRecipient: PublicKey) => {
+      const { recipient } = await program.account.message.fetch(messagePda);
+      await program.methods
+        .reassignRecipient(newRecipient)
+        .accounts({
+          message: messagePda,
+          chatRoom: chatRoomPda,
+          oldInbox: inboxPda(recipient),
+          newInbox: inboxPda(newRecipient),
+          oldRecipientStats: statsPda(recipient),
+          newRecipientStats: statsPda(newRecipient),
+          block: blockPda(newRecipient, alice.publicKey),
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+    };
+
+    await reassign(erin.publicKey);
+    expect((await program.account.message.fetch(messagePda)).recipient.toString()).to.equal(
+      erin.publicKey.toString()
+    );
+    const oldInbox = await program.account.userInbox.fetch(inboxPda(dave.publicKey));
+    expect(oldInbox.unreadCount.toNumber()).to.equal(0);
+    expect(oldInbox.recentMessages).to.be.empty;
+    const newInbox = await program.account.userInbox.fetch(inboxPda(erin.publicKey));
+    expect(newInbox.unreadCount.toNumber()).to.equal(1);
+    expect(newInbox.recentMessages.map((entry) => entry.messageId.toNumber())).to.deep.equal([messageId]);
+    const stats = await program.account.userStats.fetch(statsPda(erin.publicKey));
+    expect(stats.totalReceived.toNumber()).to.equal(1);
+    expect(stats.totalUnread.toNumber()).to.equal(1);
+
+    // Once delivered the recipient is fixed
+    await markDelivered(messagePda, erin);
+    await expectChatError(reassign(dave.publicKey), "AlreadyDelivered");
+  });
+
+  it("Saves, overwrites, promotes and deletes drafts", async () => {
+    const draft = PublicKey.findProgramAddressSync(
+      [Buffer.from("draft"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const saveDraft = (content: string) =>
+      program.methods
+        .saveDraft(bob.publicKey, Buffer.from(content), 0, Array.from(randomBytes(24)))
+        .accounts({
+          draft,
+          owner: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+
+    const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+    await saveDraft("first thoughts");
+    await saveDraft("second thoughts");
+    const saved = await program.account.draft.fetch(draft);
+    expect(Buffer.from(saved.encryptedContent).toString()).to.equal("second thoughts");
+
+    // Drafts take no message id until they are sent
+    expect((await program.account.chatRoom.fetch(chatRoomPda)).messageCount.eq(messageCount)).to.be.true;
+    const message = messagePda(messageCount);
+    await program.methods
+      .promoteDraft(new anchor.BN(0))
+      .accounts({
+        draft,
+        message,
+        chatRoom: chatRoomPda,
+        inbox: inboxPda(bob.publicKey),
+        senderState: senderStatePda(chatRoomPda, alice.publicKey),
+        block: blockPda(bob.publicKey, alice.publicKey),
+        membership: null,
+        senderStats: statsPda(alice.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const sent = await program.account.message.fetch(message);
+    expect(Buffer.from(sent.encryptedContent).toString()).to.equal("second thoughts");
+    expect(sent.nonce).to.deep.equal(saved.nonce);
+    expect(sent.recipient.toString()).to.equal(bob.publicKey.toString());
+    expect(await provider.connection.getAccountInfo(draft)).to.be.null;
+
+    await saveDraft("never mind");
+    await program.methods
+      .deleteDraft()
+      .accounts({ draft, owner: alice.publicKey })
+      .signers([alice])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(draft)).to.be.null;
+  });
+
+  it("Lets a relayer pay rent for someone else's message", async () => {
+    const relayer = Keypair.generate();
+    await airdrop(relayer.publicKey);
+    const senderBefore = await provider.connection.getBalance(bob.publicKey);
+    const relayerBefore = await provider.connection.getBalance(relayer.publicKey);
+
+    const { messagePda } = await sendMessage(bob, alice.publicKey, Buffer.from("sponsored"), {
+      payer: relayer,
+    });
+
+    const stored = await program.account.message.fetch(messagePda);
+    expect(stored.sender.toString()).to.equal(bob.publicKey.toString());
+    // The provider wallet pays the fee, so only the relayer funds rent
+    expect(await provider.connection.getBalance(bob.publicKey)).to.equal(senderBefore);
+    expect(await provider.connection.getBalance(relayer.publicKey)).to.be.lessThan(relayerBefore);
+  });
+
+  it("Keeps per-user message totals once stats are initialized", async () => {
+    const sender = Keypair.generate();
+    const reader = Keypair.generate();
+    await airdrop(sender.publicKey);
+    await airdrop(reader.publicKey);
+    for (const user of [sender, reader]) {
+      await program.methods
+        .initStats()
+        .accounts({
+          stats: statsPda(user.publicKey),
+          user: user.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+    }
+
+    const { messagePda: first } = await sendMessage(sender, reader.publicKey, Buffer.from("one"));
+    await sendMessage(sender, reader.publicKey, Buffer.from("two"));
+    // Every send instruction counts, not just send_message
+    const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+    await program.methods
+      .sendMessageSized(
+        new anchor.BN(0),
+        Array.from(Buffer.from("three")),
+        reader.publicKey,
+        0,
+        Array.from(randomBytes(24))
+      )
+      .accounts({
+        message: messagePda(messageCount),
+        chatRoom: chatRoomPda,
+        inbox: inboxPda(reader.publicKey),
+        senderState: senderStatePda(chatRoomPda, sender.publicKey),
+        block: blockPda(reader.publicKey, sender.publicKey),
+        membership: null,
+        senderStats: statsPda(sender.publicKey),
+        recipientStats: statsPda(reader.publicKey),
+        sender: sender.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([sender])
+      .rpc();
+    await markDelivered(first, reader);
+    await program.methods
+      .acknowledgeMessage()
+      .accounts({
+        message: first,
+        inbox: inboxPda(reader.publicKey),
+        recipientStats: statsPda(reader.publicKey),
+        settings: settingsPda(reader.publicKey),
+        readMarker: readMarkerPda(reader.publicKey, first),
+        recipient: reader.publicKey,
+        sender: sender.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([reader])
+      .rpc();
+
+    const sent = await program.account.userStats.fetch(statsPda(sender.publicKey));
+    expect(sent.totalSent.toNumber()).to.equal(3);
+    expect(sent.totalReceived.toNumber()).to.equal(0);
+    const received = await program.account.userStats.fetch(statsPda(reader.publicKey));
+    expect(received.totalSent.toNumber()).to.equal(0);
+    expect(received.totalReceived.toNumber()).to.equal(3);
+    expect(received.totalUnread.toNumber()).to.equal(2);
+  });
+
+  it("Skips the read receipt when the recipient has disabled them", async () => {
+    const reader = Keypair.generate();
+    await airdrop(reader.publicKey);
+    await program.methods
+      .setReadReceipts(false)
+      .accounts({
+        settings: settingsPda(reader.publicKey),
+        user: reader.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([reader])
+      .rpc();
+
+    const { messagePda } = await sendMessage(alice, reader.publicKey, Buffer.from("unseen"));
+    await markDelivered(messagePda, reader);
+    const acknowledge = () =>
+      program.methods
+        .acknowledgeMessage()
+        .accounts({
+          message: messagePda,
+          inbox: inboxPda(reader.publicKey),
+          recipientStats: statsPda(reader.publicKey),
+          settings: settingsPda(reader.publicKey),
+          readMarker: readMarkerPda(reader.publicKey, messagePda),
+          recipient: reader.publicKey,
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([reader])
+        .rpc({ commitment: "confirmed" });
+    const signature = await acknowledge();
+
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    const events = Array.from(parser.parseLogs(tx.meta.logMessages));
+    expect(events.map((e) => e.name)).to.not.include("messageRead");
+    expect((await program.account.message.fetch(messagePda)).readAt).to.be.null;
+    const inbox = await program.account.userInbox.fetch(inboxPda(reader.publicKey));
+    expect(inbox.unreadCount.toNumber()).to.equal(0);
+
+    // The reader's own marker catches a second acknowledge
+    await expectChatError(acknowledge(), "AlreadyRead");
+    // Nothing on the message records the read, so the sender may still edit
+    await program.methods
+      .editMessage(Array.from(Buffer.from("revised")))
+      .accounts({ message: messagePda, room: chatRoomPda, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+  });
+
+  it("Rejects a resubmitted send that reuses its client nonce", async () => {
+    const clientNonce = randomBytes(16);
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("once"), {
+      clientNonce,
+    });
+    expect(await provider.connection.getAccountInfo(sentMarkerPda(alice.publicKey, clientNonce))).to
+      .not.be.null;
+
+    const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, Buffer.from("once"), { clientNonce }),
+      "DuplicateSend"
+    );
+    expect((await program.account.chatRoom.fetch(chatRoomPda)).messageCount.eq(messageCount)).to.be
+      .true;
+    expect(Buffer.from((await program.account.message.fetch(messagePda)).encryptedContent).toString())
+      .to.equal("once");
+  });
+
+  it("Freezes a message once its recipient has read it", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("draft wording"));
+    const edit = (content: string) =>
+      program.methods
+        .editMessage(Array.from(Buffer.from(content)))
+        .accounts({ message: messagePda, room: chatRoomPda, sender: alice.publicKey })
+        .signers([alice])
+        .rpc();
+
+    // Fresh and unread: inside the edit window
+    await edit("final wording");
+    await markDelivered(messagePda, bob);
+    await program.methods
+      .acknowledgeMessage()
+      .accounts({
+        message: messagePda,
+        inbox: inboxPda(bob.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        settings: settingsPda(bob.publicKey),
+        readMarker: readMarkerPda(bob.publicKey, messagePda),
+        recipient: bob.publicKey,
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    await expectChatError(edit("rewritten history"), "AlreadyRead");
+    const message = await program.account.message.fetch(messagePda);
+    expect(Buffer.from(message.encryptedContent).toString()).to.equal("final wording");
+  });
+
+  it("Establishes and rotates a forward-secret session", async () => {
+    const erin = Keypair.generate();
+    await airdrop(erin.publicKey);
+    const [first, second] = sortParticipants(alice.publicKey, erin.publicKey);
+    const [signerA, signerB] = first.equals(alice.publicKey) ? [alice, erin] : [erin, alice];
+    const conversation = conversationPda(alice.publicKey, erin.publicKey);
+    await program.methods
+      .initConversation(first, second)
+      .accounts({
+        conversation,
+        initiator: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const session = PublicKey.findProgramAddressSync(
+      [Buffer.from("session"), conversation.toBuffer()],
+      program.programId
+    )[0];
+    const [keyA, keyB] = [randomBytes(32), randomBytes(32)];
+    await program.methods
+      .establishSession(Array.from(keyA), Array.from(keyB))
+      .accounts({
+        conversation,
+        session,
+        participantA: signerA.publicKey,
+        participantB: signerB.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([signerA, signerB])
+      .rpc();
+    let state = await program.account.session.fetch(session);
+    expect(state.sessionEpoch.toNumber()).to.equal(0);
+    expect(Buffer.from(state.ephemeralKeyA)).to.deep.equal(keyA);
+
+    const dm = messagePda(0, conversation);
+    await program.methods
+      .sendDirectMessage(Array.from(Buffer.from("forward secret")), 0, Array.from(randomBytes(24)), null)
+      .accounts({
+        message: dm,
+        conversation,
+        session,
+        senderStats: statsPda(erin.publicKey),
+        recipientStats: statsPda(alice.publicKey),
+        sender: erin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([erin])
+      .rpc();
+    expect((await program.account.message.fetch(dm)).sessionEpoch.toNumber()).to.equal(0);
+
+    const [nextA, nextB] = [randomBytes(32), randomBytes(32)];
+    await program.methods
+      .rotateSession(Array.from(nextA), Array.from(nextB))
+      .accounts({
+        conversation,
+        session,
+        participantA: signerA.publicKey,
+        participantB: signerB.publicKey,
+      })
+      .signers([signerA, signerB])
+      .rpc();
+    state = await program.account.session.fetch(session);
+    expect(state.sessionEpoch.toNumber()).to.equal(1);
+    expect(Buffer.from(state.ephemeralKeyB)).to.deep.equal(nextB);
+  });
+
+  it("Restricts system messages to the room authority", async () => {
+    const notice = async (authority: Keypair) => {
+      const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+      const message = messagePda(messageCount);
+      await program.methods
+        .sendSystemMessage(
+          new anchor.BN(0),
+          Array.from(Buffer.from("carol joined")),
+          0,
+          Array.from(randomBytes(24))
+        )
+        .accounts({
+          message,
+          chatRoom: chatRoomPda,
+          senderStats: statsPda(authority.publicKey),
+          authority: authority.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([authority])
+        .rpc();
+      return message;
+    };
+
+    await expectChatError(notice(bob), "Unauthorized");
+
+    const message = await program.account.message.fetch(await notice(alice));
+    expect(message.isSystem).to.be.true;
+    expect(message.recipient.toString()).to.equal(chatRoomPda.toString());
+    const { messagePda: regular } = await sendMessage(alice, bob.publicKey, Buffer.from("hi"));
+    expect((await program.account.message.fetch(regular)).isSystem).to.be.false;
+  });
+
+  it("Purges a range of room messages in authority sweeps", async () => {
+    const room = await initializeRoom(22);
+    const sent = [];
+    for (const [sender, recipient] of [
+      [alice, bob],
+      [bob, alice],
+      [alice, bob],
+    ] as const) {
+      const { messagePda } = await sendMessage(sender, recipient.publicKey, Buffer.from("wipe"), {
+        roomId: 22,
+      });
+      sent.push(messagePda);
+    }
+    await program.methods
+      .deleteMessage()
+      .accounts({ message: sent[1], parent: null, sender: bob.publicKey })
+      .signers([bob])
+      .rpc();
+
+    const purge = (authority: Keypair, startId: number, senders: PublicKey[]) =>
+      program.methods
+        .purgeMessages(new anchor.BN(startId), senders.length)
+        .accounts({ chatRoom: room, authority: authority.publicKey })
+        .remainingAccounts(
+          senders.flatMap((sender, i) => [
+            { pubkey: messagePda(startId + i, room), isSigner: false, isWritable: true },
+            { pubkey: sender, isSigner: false, isWritable: true },
+          ])
+        )
+        .signers([authority]);
+
+    await expectChatError(purge(bob, 0, [alice.publicKey]).rpc(), "Unauthorized");
+    await expectChatError(
+      purge(alice, 2, [alice.publicKey, alice.publicKey]).rpc(),
+      "InvalidPurgeRange"
+    );
+
+    // The deleted message's slot takes any placeholder sender
+    const senders = [alice.publicKey, alice.publicKey];
+    const cursor = await purge(alice, 0, senders).view();
+    expect(cursor.toNumber()).to.equal(2);
+    await purge(alice, 0, senders).rpc();
+    await purge(alice, 2, [alice.publicKey]).rpc();
+
+    for (const message of sent) {
+      expect(await provider.connection.getAccountInfo(message)).to.be.null;
+    }
+  });
+
+  it("Destroys a view-once message when it is read", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("burn after reading"), {
+      viewOnce: true,
+    });
+    expect((await program.account.message.fetch(messagePda)).viewOnce).to.be.true;
+    await markDelivered(messagePda, bob);
+
+    let listener: number;
+    const read = new Promise<any>((resolve) => {
+      listener = program.addEventListener("messageRead", (e) => resolve(e));
+    });
+    const senderBefore = await provider.connection.getBalance(alice.publicKey);
+    await program.methods
+      .acknowledgeMessage()
+      .accounts({
+        message: messagePda,
+        inbox: inboxPda(bob.publicKey),
+        recipientStats: statsPda(bob.publicKey),
+        settings: settingsPda(bob.publicKey),
+        readMarker: readMarkerPda(bob.publicKey, messagePda),
+        recipient: bob.publicKey,
+        sender: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const event = await read;
+    await program.removeEventListener(listener);
+    expect(event.reader.toString()).to.equal(bob.publicKey.toString());
+    expect(await provider.connection.getAccountInfo(messagePda)).to.be.null;
+    expect(await provider.connection.getBalance(alice.publicKey)).to.be.greaterThan(senderBefore);
+  });
+
+  it("Sizes message accounts to their ciphertext with send_message_sized", async () => {
+    const sendSized = async (content: Buffer) => {
+      const { messageCount } = await program.account.chatRoom.fetch(chatRoomPda);
+      const message = messagePda(messageCount);
+      await program.methods
+        .sendMessageSized(
+          new anchor.BN(0),
+          Array.from(content),
+          bob.publicKey,
+          0,
+          Array.from(randomBytes(24))
+        )
+        .accounts({
+          message,
+          chatRoom: chatRoomPda,
+          inbox: inboxPda(bob.publicKey),
+          senderState: senderStatePda(chatRoomPda, alice.publicKey),
+          block: blockPda(bob.publicKey, alice.publicKey),
+          membership: null,
+          senderStats: statsPda(alice.publicKey),
+          recipientStats: statsPda(bob.publicKey),
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      return message;
+    };
+
+    const shortMessage = await sendSized(randomBytes(20));
+    const short = await provider.connection.getAccountInfo(shortMessage);
+    const full = await provider.connection.getAccountInfo(await sendSized(randomBytes(512)));
+    expect(short.data.length).to.be.lessThan(full.data.length);
+    expect(short.lamports).to.be.lessThan(full.lamports * 0.7);
+
+    // Still editable in place as long as the content does not grow
+    await program.methods
+      .editMessage(Array.from(randomBytes(20)))
+      .accounts({ message: shortMessage, room: chatRoomPda, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+    expect((await program.account.message.fetch(shortMessage)).editCount).to.equal(1);
+  });
+
+  it("Distinguishes delete-for-me from delete-for-everyone", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("regrettable"));
+    const hiddenPda = (user: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("hidden"), user.toBuffer(), messagePda.toBuffer()],
+        program.programId
+      )[0];
+    const hide = (user: Keypair) =>
+      program.methods
+        .hideMessage()
+        .accounts({
+          message: messagePda,
+          hidden: hiddenPda(user.publicKey),
+          user: user.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+    const carol = Keypair.generate();
+    await airdrop(carol.publicKey);
+    await expectChatError(hide(carol), "NotAParticipant");
+
+    // Delete for me: only the recipient's view changes
+    await hide(bob);
+    expect(await provider.connection.getAccountInfo(hiddenPda(bob.publicKey))).to.not.be.null;
+    expect(await provider.connection.getAccountInfo(hiddenPda(alice.publicKey))).to.be.null;
+    expect(await provider.connection.getAccountInfo(messagePda)).to.not.be.null;
+
+    // Delete for everyone: the message account itself is closed
+    await program.methods
+      .deleteMessage()
+      .accounts({ message: messagePda, parent: null, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(messagePda)).to.be.null;
+
+    await program.methods
+      .unhideMessage()
+      .accounts({ hidden: hiddenPda(bob.publicKey), user: bob.publicKey })
+      .signers([bob])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(hiddenPda(bob.publicKey))).to.be.null;
+  });
+
+  it("Throttles the whole room in slow mode", async () => {
+    const room = await initializeRoom(23);
+    const setSlowMode = (secs: number) =>
+      program.methods
+        .setSlowMode(secs)
+        .accounts({ chatRoom: room, authority: alice.publicKey })
+        .signers([alice])
+        .rpc();
+    const send = (sender: Keypair, recipient: Keypair) =>
+      sendMessage(sender, recipient.publicKey, Buffer.from("slowly"), { roomId: 23 });
+
+    await setSlowMode(3);
+    // System notices are not gated and do not start the interval
+    const { messageCount } = await program.account.chatRoom.fetch(room);
+    await program.methods
+      .sendSystemMessage(
+        new anchor.BN(23),
+        Array.from(Buffer.from("slow mode is on")),
+        0,
+        Array.from(randomBytes(24))
+      )
+      .accounts({
+        message: messagePda(messageCount, room),
+        chatRoom: room,
+        senderStats: statsPda(alice.publicKey),
+        authority: alice.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+    await send(alice, bob);
+    // A different sender is held back too
+    await expectChatError(send(bob, alice), "SlowMode");
+
+    const { lastRoomMessageAt } = await program.account.chatRoom.fetch(room);
+    while ((await chainTime()) < lastRoomMessageAt.toNumber() + 3) {
+      await new Promise((resolve) => setTimeout(resolve, 500));
+    }
+    // A batch passes slow mode as one send, however many recipients it has
+    const recipients = [alice.publicKey, Keypair.generate().publicKey];
+    const next = (await program.account.chatRoom.fetch(room)).messageCount;
+    await program.methods
+      .batchSend(
+        new anchor.BN(23),
+        recipients,
+        recipients.map(() => Buffer.from("slowly, together")),
+        0,
+        recipients.map(() => Array.from(randomBytes(24)))
+      )
+      .accounts({
+        chatRoom: room,
+        senderState: senderStatePda(room, bob.publicKey),
+        membership: null,
+        senderStats: statsPda(bob.publicKey),
+        sender: bob.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .remainingAccounts(
+        recipients.flatMap((recipient, i) =>
+          [
+            messagePda(next.addn(i), room),
+            inboxPda(recipient),
+            blockPda(recipient, bob.publicKey),
+            statsPda(recipient),
+          ].map((pubkey) => ({ pubkey, isSigner: false, isWritable: true }))
+        )
+      )
+      .signers([bob])
+      .rpc();
+
+    await setSlowMode(0);
+    await send(alice, bob);
+    await send(bob, alice);
+    expect((await program.account.chatRoom.fetch(room)).messageCount.toNumber()).to.equal(6);
+  });
+
+  it("Counts live replies on the parent message", async () => {
+    const parent = await sendMessage(alice, bob.publicKey, Buffer.from("thread root"));
+    const replies = [];
+    for (const sender of [bob, alice]) {
+      replies.push(
+        await sendMessage(sender, alice.publicKey, Buffer.from("reply"), { replyTo: parent.messageId })
+      );
+    }
+    expect((await program.account.message.fetch(parent.messagePda)).replyCount).to.equal(2);
+
+    await program.methods
+      .deleteMessage()
+      .accounts({ message: replies[0].messagePda, parent: parent.messagePda, sender: bob.publicKey })
+      .signers([bob])
+      .rpc();
+    expect((await program.account.message.fetch(parent.messagePda)).replyCount).to.equal(1);
+
+    // Recalling a reply takes it off too
+    await program.methods
+      .unsendMessage()
+      .accounts({ message: replies[1].messagePda, parent: parent.messagePda, sender: alice.publicKey })
+      .signers([alice])
+      .rpc();
+    expect((await program.account.message.fetch(parent.messagePda)).replyCount).to.equal(0);
+  });
+
+  it("Stars messages independently per user", async () => {
+    const { messagePda } = await sendMessage(alice, bob.publicKey, Buffer.from("remember this"));
+    const starPda = (user: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("star"), user.toBuffer(), messagePda.toBuffer()],
+        program.programId
+      )[0];
+    const star = (user: Keypair) =>
+      program.methods
+        .starMessage()
+        .accounts({
+          message: messagePda,
+          starred: starPda(user.publicKey),
+          user: user.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+    const unstar = (user: Keypair) =>
+      program.methods
+        .unstarMessage()
+        .accounts({ message: messagePda, starred: starPda(user.publicKey), user: user.publicKey })
+        .signers([user])
+        .rpc();
+    const starCount = async () => (await program.account.message.fetch(messagePda)).starCount;
+
+    await star(bob);
+    await star(alice);
+    expect(await starCount()).to.equal(2);
+    const starred = await program.account.starredMessage.fetch(starPda(bob.publicKey));
+    expect(starred.message.toString()).to.equal(messagePda.toString());
+
+    await unstar(bob);
+    expect(await starCount()).to.equal(1);
+    expect(await provider.connection.getAccountInfo(starPda(bob.publicKey))).to.be.null;
+    expect(await provider.connection.getAccountInfo(starPda(alice.publicKey))).to.not.be.null;
+  });
+
+  it("Advances a per-conversation read cursor but never rewinds it", async () => {
+    const cursor = PublicKey.findProgramAddressSync(
+      [Buffer.from("cursor"), bob.publicKey.toBuffer(), chatRoomPda.toBuffer()],
+      program.programId
+    )[0];
+    const setCursor = (messageId: number) =>
+      program.methods
+        .setReadCursor(new anchor.BN(messageId))
+        .accounts({
+          conversation: chatRoomPda,
+          cursor,
+          user: bob.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    await setCursor(3);
+    await setCursor(5);
+    // Re-sending the same position is a harmless retry
+    await setCursor(5);
+    await expectChatError(setCursor(4), "CursorRegression");
+    const stored = await program.account.readCursor.fetch(cursor);
+    expect(stored.lastReadMessageId.toNumber()).to.equal(5);
+  });
+
+  it("Stores the parent hash a reply quotes and rejects a stale quote", async () => {
+    const parent = await sendMessage(alice, bob.publicKey, Buffer.from("quoted parent"));
+    const { contentHash } = await program.account.message.fetch(parent.messagePda);
+    const quotedContentHash = Buffer.from(contentHash);
+
+    const reply = await sendMessage(bob, alice.publicKey, Buffer.from("quoting reply"), {
+      replyTo: parent.messageId,
+      quotedContentHash,
+    });
+    const stored = await program.account.message.fetch(reply.messagePda);
+    expect(Buffer.from(stored.quotedContentHash)).to.deep.equal(quotedContentHash);
+
+    await expectChatError(
+      sendMessage(bob, alice.publicKey, Buffer.from("stale quote"), {
+        replyTo: parent.messageId,
+        quotedContentHash: Buffer.alloc(32, 1),
+      }),
+      "QuoteMismatch"
+    );
+  });
+
+  it("Stamps messages with the room's security epoch across a bump", async () => {
+    const roomId = 24;
+    const room = await initializeRoom(roomId);
+    const before = await sendMessage(alice, bob.publicKey, Buffer.from("before"), { roomId });
+
+    await program.methods
+      .bumpSecurityEpoch()
+      .accounts({ chatRoom: room, authority: alice.publicKey })
+      .signers([alice])
+      .rpc();
+    const after = await sendMessage(alice, bob.publicKey, Buffer.from("after"), { roomId });
+
+    const epochOf = async (message: PublicKey) =>
+      (await program.account.message.fetch(message)).securityEpoch.toNumber();
+    expect(await epochOf(before.messagePda)).to.equal(0);
+    expect(await epochOf(after.messagePda)).to.equal(1);
+    expect((await program.account.chatRoom.fetch(room)).securityEpoch.toNumber()).to.equal(1);
+
+    await expectChatError(
+      program.methods
+        .bumpSecurityEpoch()
+        .accounts({ chatRoom: room, authority: bob.publicKey })
+        .signers([bob])
+        .rpc(),
+      "Unauthorized"
+    );
+  });
+
+  it("Gates sends on the sender's balance when the room sets a minimum", async () => {
+    const roomId = 25;
+    await initializeRoom(roomId, alice, {
+      minSenderBalance: new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL),
+    });
+    const poor = Keypair.generate();
+    const rich = Keypair.generate();
+    await airdrop(poor.publicKey, 1);
+    await airdrop(rich.publicKey, 3);
+
+    await expectChatError(
+      sendMessage(poor, bob.publicKey, Buffer.from("too poor"), { roomId }),
+      "InsufficientBalance"
+    );
+    await sendMessage(rich, bob.publicKey, Buffer.from("well funded"), { roomId });
+    // The rent this send pays does not count against the minimum
+    const exact = Keypair.generate();
+    await airdrop(exact.publicKey, 2);
+    await sendMessage(exact, bob.publicKey, Buffer.from("just enough"), { roomId });
+    // Room 0 has no minimum, so the same sender gets through there
+    await sendMessage(poor, bob.publicKey, Buffer.from("ungated"));
+  });
+
+  it("Reports failures with stable numeric ChatError codes", async () => {
+    try {
+      await sendMessage(alice, bob.publicKey, Buffer.alloc(0));
+    } catch (error) {
+      expect(error.error?.errorCode?.code).to.equal("EmptyMessage");
+      expect(error.error?.errorCode?.number).to.equal(6003);
+      return;
+    }
+    expect.fail("Expected an empty message to be rejected");
+  });
+
+  it("Persists the compressed flag and rejects too-short compressed payloads", async () => {
+    const payload = randomBytes(40);
+    const { messagePda } = await sendMessage(alice, bob.publicKey, payload, { compressed: true });
+    const message = await program.account.message.fetch(messagePda);
+    expect(message.compressed).to.be.true;
+    expect(Buffer.from(message.encryptedContent)).to.deep.equal(payload);
+
+    await expectChatError(
+      sendMessage(alice, bob.publicKey, randomBytes(4), { compressed: true }),
+      "InvalidCompressedPayload"
+    );
+    // The same length is fine when the content is not flagged as compressed
+    await sendMessage(alice, bob.publicKey, randomBytes(4));
+  });
+
+  it("Sends one group ciphertext with a sealed key per member", async () => {
+    const roomId = 26;
+    const room = await initializeRoom(roomId, alice, { gated: true });
+    const carol = Keypair.generate();
+    for (const member of [alice, bob, carol]) {
+      await addMember(room, member.publicKey);
+    }
+
+    const sendGroup = async (sealedKeys: Buffer[]) => {
+      const { messageCount } = await program.account.chatRoom.fetch(room);
+      const message = messagePda(messageCount, room);
+      await program.methods
+        .sendGroupMessage(
+          new anchor.BN(roomId),
+          Buffer.from("group secret"),
+          sealedKeys.map((key) => Array.from(key)),
+          0,
+          Array.from(randomBytes(24))
+        )
+        .accounts({
+          message,
+          chatRoom: room,
+          senderState: senderStatePda(room, alice.publicKey),
+          membership: membershipPda(room, alice.publicKey),
+          senderStats: statsPda(alice.publicKey),
+          sender: alice.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      return message;
+    };
+
+    const sealedKeys = [1, 2, 3].map((fill) => Buffer.alloc(48, fill));
+    const message = await program.account.message.fetch(await sendGroup(sealedKeys));
+    expect(message.sealedKeys).to.have.length(3);
+    sealedKeys.forEach((key, index) =>
+      expect(Buffer.from(message.sealedKeys[index])).to.deep.equal(key)
+    );
+
+    await expectChatError(sendGroup(sealedKeys.slice(0, 2)), "SealedKeyCountMismatch");
+  });
 });